@@ -0,0 +1,42 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, Options};
+use tempfile::TempDir;
+
+const KEYS: usize = 1000;
+
+// Compare `get` through buffered file reads and through memory maps,
+// on data files that are immutable after a compaction.
+fn get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    for value_len in [16, 1024, 64 * 1024] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open(temp_dir.path()).unwrap();
+        for key_id in 0..KEYS {
+            store
+                .set(format!("key{}", key_id), "v".repeat(value_len))
+                .unwrap();
+        }
+        store.compact_now().unwrap();
+        drop(store);
+
+        for mmap_reads in [false, true] {
+            let options = Options {
+                mmap_reads,
+                ..Options::default()
+            };
+            let mut store = KvStore::open_with(temp_dir.path(), options).unwrap();
+            let name = if mmap_reads { "mmap" } else { "buffered" };
+            group.bench_with_input(BenchmarkId::new(name, value_len), &value_len, |b, _| {
+                let mut key_id = 0;
+                b.iter(|| {
+                    key_id = (key_id + 7) % KEYS;
+                    store.get(format!("key{}", key_id)).unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get_bench);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+/// Writes applied atomically by `KvStore::write_batch`, possibly spanning column families.
+/// After a crash either every write of the batch is in the store or none is.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Set {
+        family: Option<String>,
+        key: String,
+        value: String,
+    },
+    Remove {
+        family: Option<String>,
+        key: String,
+    },
+}
+
+impl WriteBatch {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a key of the default column family
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            family: None,
+            key,
+            value,
+        });
+        self
+    }
+
+    /// Set a key of a column family
+    pub fn set_cf(&mut self, family: &str, key: String, value: String) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            family: Some(family.to_owned()),
+            key,
+            value,
+        });
+        self
+    }
+
+    /// Remove a key of the default column family, nothing is written if it does not exist
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.ops.push(BatchOp::Remove { family: None, key });
+        self
+    }
+
+    /// Remove a key of a column family, nothing is written if it does not exist
+    pub fn remove_cf(&mut self, family: &str, key: String) -> &mut Self {
+        self.ops.push(BatchOp::Remove {
+            family: Some(family.to_owned()),
+            key,
+        });
+        self
+    }
+
+    /// Number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// `true` if the batch has no writes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,31 @@
+use clap::{Parser, ValueEnum};
+use kvs::{KvStore, KvsServer, LsmStore, MemStore, Result};
+use std::env;
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// address to listen on, IP:PORT
+    #[arg(long, default_value = "127.0.0.1:4000")]
+    addr: String,
+    /// storage engine, must match the engine that created the directory
+    #[arg(long, value_enum, default_value_t = Engine::Kvs)]
+    engine: Engine,
+}
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Engine {
+    /// log-structured hash table
+    Kvs,
+    /// LSM tree, keeps keys sorted
+    Lsm,
+    /// in memory, nothing is written to disk
+    Mem,
+}
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let dir = env::current_dir()?;
+    match cli.engine {
+        Engine::Kvs => KvsServer::new(KvStore::open(dir)?).run(cli.addr),
+        Engine::Lsm => KvsServer::new(LsmStore::open(dir)?).run(cli.addr),
+        Engine::Mem => KvsServer::new(MemStore::new()).run(cli.addr),
+    }
+}
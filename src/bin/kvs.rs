@@ -1,34 +1,107 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use kvs::{Commands, KvErr, KvStore, Result};
-use serde::{Deserialize, Serialize};
 use std::env;
-#[derive(Deserialize, Serialize, Debug, Parser)]
+#[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
-    cmd: Option<Commands>,
+    cmd: Option<CliCommand>,
+}
+/// `Commands`也是client和server之间传输的命令，只在命令行中使用的子命令放在这里
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    #[command(flatten)]
+    Store(Commands),
+    /// get the values of many keys, one line per key
+    GetMany {
+        #[arg(required = true)]
+        keys: Vec<String>,
+    },
+    /// set many keys, given as KEY VALUE KEY VALUE ...
+    SetMany {
+        #[arg(required = true, num_args = 2.., value_names = ["KEY", "VALUE"])]
+        pairs: Vec<String>,
+    },
+    /// add DELTA to the integer value of a key and print the result, a missing key counts as 0
+    Incr {
+        key: String,
+        #[arg(default_value_t = 1, allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// compact the whole store now
+    Compact,
+    /// print store statistics, the store is opened read-only so a running server
+    /// does not block it. Bloom filter and cache counters only cover this process
+    Stats {
+        /// print as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 fn main() -> Result<()> {
     let cli = Cli::parse();
     // println!("current dir {:?}", env::current_dir()?);
+    if let Some(CliCommand::Stats { json }) = cli.cmd {
+        // 只读打开，不需要写锁，也不会触发压缩
+        let stats = KvStore::open_read_only(env::current_dir()?)?.stats();
+        if json {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        } else {
+            print!("{}", stats);
+        }
+        return Ok(());
+    }
     let mut store = KvStore::open(env::current_dir()?)?;
     match cli.cmd {
-        Some(Commands::Get { key }) => match store.get(key)? {
+        Some(CliCommand::Store(Commands::Get { key })) => match store.get(key)? {
             Some(val) => println!("{}", val),
             None => println!("Key not found"),
         },
-        Some(Commands::Set { key, value } )=> {
+        Some(CliCommand::Store(Commands::Set { key, value })) => {
             if let Err(err) = store.set(key, value) {
                 println!("{}", err);
                 std::process::exit(1);
             }
         }
-        Some(Commands::Rm { key }) => {
+        Some(CliCommand::Store(Commands::Rm { key })) => {
             if let Err(KvErr::KeyNotFound) = store.remove(key) {
                 println!("Key not found");
                 std::process::exit(1);
             }
         }
+        Some(CliCommand::GetMany { keys }) => {
+            for val in store.get_many(&keys)? {
+                match val {
+                    Some(val) => println!("{}", val),
+                    None => println!("Key not found"),
+                }
+            }
+        }
+        Some(CliCommand::SetMany { pairs }) => {
+            if pairs.len() % 2 != 0 {
+                println!("missing value for key {}", pairs[pairs.len() - 1]);
+                std::process::exit(1);
+            }
+            let mut pairs = pairs.into_iter();
+            let pairs = std::iter::from_fn(|| Some((pairs.next()?, pairs.next()?))).collect();
+            if let Err(err) = store.set_many(pairs) {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Some(CliCommand::Incr { key, delta }) => match store.incr(key, delta) {
+            Ok(val) => println!("{}", val),
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        Some(CliCommand::Compact) => {
+            if let Err(err) = store.compact_now() {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
         _ => {
             std::process::exit(1);
         }
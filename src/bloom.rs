@@ -0,0 +1,99 @@
+use crate::{KvErr, Result};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+
+/// false positive rate of filters when `Options::bloom_fp_rate` is not set
+pub(crate) const DEFAULT_FP_RATE: f64 = 0.01;
+
+/// Bloom filter of the keys of one file.
+///
+/// 用两个hash的组合`h1 + i * h2`模拟k个hash函数。
+/// 文件格式：4个字节的hash个数，后面是bit数组，都是little endian
+pub(crate) struct Bloom {
+    hashes: u32,
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    /// Build a filter of the given key hashes with a target false positive rate
+    pub fn build(key_hashes: &[u64], fp_rate: f64) -> Bloom {
+        let items = key_hashes.len().max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bit_count = (-items * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = ((bit_count / items) * ln2).round().clamp(1.0, 30.0) as u32;
+        let mut bloom = Bloom {
+            hashes,
+            bits: vec![0; (bit_count as usize).div_ceil(64)],
+        };
+        for hash in key_hashes {
+            for bit in bloom.bit_positions(*hash) {
+                bloom.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    /// `false` if the key is certainly not in the filter
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key_hash(key))
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let bit_count = self.bits.len() as u64 * 64;
+        let h2 = mix(hash) | 1;
+        (0..self.hashes as u64)
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut buf = Vec::with_capacity(4 + self.bits.len() * 8);
+        buf.extend_from_slice(&self.hashes.to_le_bytes());
+        for word in &self.bits {
+            buf.extend_from_slice(&word.to_le_bytes());
+        }
+        let mut file = File::create(path)?;
+        file.write_all(&buf)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Load the filter at `path`, `None` if the file does not exist
+    pub fn load(path: &Path) -> Result<Option<Bloom>> {
+        let mut buf = Vec::new();
+        match File::open(path) {
+            Ok(mut file) => file.read_to_end(&mut buf)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if buf.len() < 12 || (buf.len() - 4) % 8 != 0 {
+            return Err(KvErr::Corrupted(format!(
+                "invalid bloom filter {}",
+                path.display()
+            )));
+        }
+        let (hashes, bits) = buf.split_at(4);
+        Ok(Some(Bloom {
+            hashes: u32::from_le_bytes(hashes.try_into().unwrap()),
+            bits: bits
+                .chunks_exact(8)
+                .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                .collect(),
+        }))
+    }
+}
+
+/// 64位FNV-1a，写入文件的filter依赖这个hash，不能用版本间不稳定的`DefaultHasher`
+pub(crate) fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// splitmix64的最后一步，由第一个hash得到第二个hash
+fn mix(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
@@ -0,0 +1,64 @@
+use crate::lru::LruCache;
+use crate::CacheStats;
+
+/// Cache of decoded values of hot keys, kept by `KvStore` when
+/// `Options::value_cache_bytes` is set.
+///
+/// 写入和删除时由KvStore让对应的key失效，缓存中的value总是和磁盘上最新的记录一致
+pub(crate) struct ValueCache {
+    entries: LruCache<String, String>,
+    capacity: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: u64) -> Self {
+        ValueCache {
+            entries: LruCache::new(capacity),
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn get(&mut self, key: &String) -> Option<String> {
+        if !self.enabled() {
+            return None;
+        }
+        let value = self.entries.get(key).cloned();
+        match value {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        value
+    }
+
+    pub fn insert(&mut self, key: String, value: String) {
+        if self.enabled() {
+            let weight = (key.len() + value.len()) as u64;
+            self.entries.insert(key, value, weight);
+        }
+    }
+
+    pub fn invalidate(&mut self, key: &String) {
+        self.entries.remove(key);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            bytes: self.entries.weight(),
+            capacity_bytes: self.capacity,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::protocol::{Reply, Request, Response};
+use crate::{Commands, KvErr, Result};
+use serde_json::de::IoRead;
+use serde_json::{Deserializer, StreamDeserializer};
+use std::collections::HashMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// number of requests of a batch sent before their responses are read
+const MAX_IN_FLIGHT: usize = 256;
+
+/// KvStore client, pipelines requests on a single connection
+pub struct KvsClient {
+    reader: StreamDeserializer<'static, IoRead<BufReader<TcpStream>>, Response>,
+    writer: BufWriter<TcpStream>,
+    next_id: u64,
+    /// responses that arrived before their caller asked for them
+    pending: HashMap<u64, Reply>,
+}
+
+impl KvsClient {
+    /// Connect to a `KvsServer` at the given address
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        let reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
+        Ok(KvsClient {
+            reader: reader.into_iter(),
+            writer: BufWriter::new(stream),
+            next_id: 0,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Get the value of a key from the server
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let id = self.send(Commands::Get { key })?;
+        self.flush()?;
+        Self::expect_value(self.recv(id)?)
+    }
+
+    /// Set the value of a key on the server
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let id = self.send(Commands::Set { key, value })?;
+        self.flush()?;
+        Self::expect_ok(self.recv(id)?)
+    }
+
+    /// Remove a key on the server
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let id = self.send(Commands::Rm { key })?;
+        self.flush()?;
+        Self::expect_ok(self.recv(id)?)
+    }
+
+    /// Get the values of many keys, pipelined on the connection.
+    /// Values are returned in the same order as the keys.
+    pub fn multi_get(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.pipeline(keys.into_iter().map(|key| Commands::Get { key }))?
+            .into_iter()
+            .map(|reply| reply.and_then(Self::expect_value))
+            .collect()
+    }
+
+    /// Set many key/value pairs, pipelined on the connection.
+    /// Every pair is sent even if an earlier one fails; the first error is returned.
+    pub fn multi_set(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let cmds = pairs
+            .into_iter()
+            .map(|(key, value)| Commands::Set { key, value });
+        self.pipeline(cmds)?
+            .into_iter()
+            .try_for_each(|reply| reply.and_then(Self::expect_ok))
+    }
+
+    /// 每次最多发送MAX_IN_FLIGHT个请求，读完它们的响应再继续。
+    /// 服务端每个响应都会flush，如果一次发送所有请求，两个方向的TCP缓冲区都满了之后
+    /// 双方都会阻塞在写上
+    fn pipeline(&mut self, cmds: impl Iterator<Item = Commands>) -> Result<Vec<Result<Reply>>> {
+        let mut replies = Vec::new();
+        let mut ids = Vec::with_capacity(MAX_IN_FLIGHT);
+        let mut cmds = cmds.peekable();
+        while cmds.peek().is_some() {
+            for cmd in cmds.by_ref().take(MAX_IN_FLIGHT) {
+                ids.push(self.send(cmd)?);
+            }
+            self.flush()?;
+            replies.extend(ids.drain(..).map(|id| self.recv(id)));
+        }
+        Ok(replies)
+    }
+
+    /// Queue a request without waiting for its response, returns the request id.
+    /// The request is buffered until `flush` is called.
+    pub fn send(&mut self, cmd: Commands) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        serde_json::to_writer(&mut self.writer, &Request { id, cmd })?;
+        Ok(id)
+    }
+
+    /// Write every queued request to the server
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Wait for the response of the given request.
+    /// Responses of other requests read meanwhile are kept for their own `recv`.
+    pub fn recv(&mut self, id: u64) -> Result<Reply> {
+        if let Some(reply) = self.pending.remove(&id) {
+            return Ok(reply);
+        }
+        loop {
+            match self.reader.next() {
+                Some(resp) => {
+                    let resp = resp?;
+                    if resp.id == id {
+                        return Ok(resp.reply);
+                    }
+                    self.pending.insert(resp.id, resp.reply);
+                }
+                None => return Err(KvErr::StringError("connection closed".to_owned())),
+            }
+        }
+    }
+
+    fn expect_value(reply: Reply) -> Result<Option<String>> {
+        match reply {
+            Reply::Value(value) => Ok(value),
+            Reply::Err(msg) => Err(KvErr::StringError(msg)),
+            Reply::Ok => Err(KvErr::UnknownCommand),
+        }
+    }
+
+    fn expect_ok(reply: Reply) -> Result<()> {
+        match reply {
+            Reply::Ok => Ok(()),
+            Reply::Err(msg) => Err(KvErr::StringError(msg)),
+            Reply::Value(_) => Err(KvErr::UnknownCommand),
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use crate::kv::KvEntry;
+use crate::lru::LruCache;
+use crate::sstable::{remove_table, search_page, Table, TableWriter};
+use crate::{BloomStats, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Latest record of a key
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Slot {
+    Live(KvEntry),
+    /// the key was removed by this tombstone
+    Dead(KvEntry),
+}
+
+/// Key index kept in a sorted index file, for stores with more keys than memory.
+///
+/// 索引文件是一个`Table`，内存中只有它的稀疏索引、Bloom filter、最近读过的page和上次checkpoint之后的写入，
+/// 查找一个key最多读一个page，不存在的key通常一个page也不读。
+/// page cache和统计放在Mutex中，查找只需要`&self`，store也可以在线程之间共享
+pub(crate) struct DiskIndex {
+    dir_path: PathBuf,
+    /// id and table of the index of the last checkpoint
+    base: Option<(u64, Table)>,
+    cache: Mutex<LruCache<usize, Vec<(String, Slot)>>>,
+    /// writes since the last checkpoint
+    delta: BTreeMap<String, Slot>,
+    /// false positive rate of the filters of new index files
+    fp_rate: f64,
+    bloom: Mutex<BloomStats>,
+}
+
+impl DiskIndex {
+    /// Open the index file `id`, or an empty index
+    pub fn open(
+        dir_path: &Path,
+        id: Option<u64>,
+        cache_pages: usize,
+        fp_rate: f64,
+    ) -> Result<Self> {
+        let mut index = DiskIndex {
+            dir_path: dir_path.to_owned(),
+            base: None,
+            cache: Mutex::new(LruCache::new(cache_pages as u64)),
+            delta: BTreeMap::new(),
+            fp_rate,
+            bloom: Mutex::new(BloomStats::default()),
+        };
+        if let Some(id) = id {
+            index.load(id)?;
+        }
+        Ok(index)
+    }
+
+    /// id of the index file in use
+    pub fn id(&self) -> Option<u64> {
+        self.base.as_ref().map(|(id, _)| *id)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Slot>> {
+        if let Some(slot) = self.delta.get(key) {
+            return Ok(Some(*slot));
+        }
+        let (_, table) = match &self.base {
+            Some(base) => base,
+            None => return Ok(None),
+        };
+        let mut bloom = lock(&self.bloom);
+        bloom.checks += 1;
+        let slot = if !table.may_contain(key) {
+            bloom.skipped_reads += 1;
+            None
+        } else {
+            let slot = match table.find_page(key) {
+                Some(page_no) => self.search(table, page_no, key)?,
+                None => None,
+            };
+            if slot.is_none() {
+                bloom.false_positives += 1;
+            }
+            slot
+        };
+        Ok(slot)
+    }
+
+    /// 在page中查找key，page不在cache中时读取并缓存
+    fn search(&self, table: &Table, page_no: usize, key: &str) -> Result<Option<Slot>> {
+        let mut cache = lock(&self.cache);
+        if let Some(page) = cache.get(&page_no) {
+            return Ok(search_page(page, key).copied());
+        }
+        let page = table.read_page(page_no)?;
+        let slot = search_page(&page, key).copied();
+        cache.insert(page_no, page, 1);
+        Ok(slot)
+    }
+
+    pub fn bloom_stats(&self) -> BloomStats {
+        *lock(&self.bloom)
+    }
+
+    pub fn put(&mut self, key: String, slot: Slot) {
+        self.delta.insert(key, slot);
+    }
+
+    /// number of writes since the last checkpoint
+    pub fn delta_len(&self) -> usize {
+        self.delta.len()
+    }
+
+    /// Write the index file `id` with every key and slot of the index mapped by `f`,
+    /// a key is dropped when `f` returns `None`. The index itself is unchanged
+    /// until `install`.
+    pub fn rewrite(
+        &self,
+        id: u64,
+        mut f: impl FnMut(&str, Slot) -> Result<Option<Slot>>,
+    ) -> Result<()> {
+        let mut writer = TableWriter::create(&index_path(&self.dir_path, id), self.fp_rate)?;
+        let mut delta = self.delta.iter().peekable();
+        // 顺序读取旧的索引文件，和delta按key归并，delta中的key更新
+        if let Some((_, table)) = &self.base {
+            for page_no in 0..table.page_count() {
+                for (key, slot) in table.read_page::<Slot>(page_no)? {
+                    while let Some((delta_key, delta_slot)) =
+                        delta.next_if(|(delta_key, _)| delta_key.as_str() < key.as_str())
+                    {
+                        if let Some(slot) = f(delta_key, *delta_slot)? {
+                            writer.push(delta_key, &slot)?;
+                        }
+                    }
+                    let slot = match delta.next_if(|(delta_key, _)| **delta_key == key) {
+                        Some((_, delta_slot)) => *delta_slot,
+                        None => slot,
+                    };
+                    if let Some(slot) = f(&key, slot)? {
+                        writer.push(&key, &slot)?;
+                    }
+                }
+            }
+        }
+        for (key, slot) in delta {
+            if let Some(slot) = f(key, *slot)? {
+                writer.push(key, &slot)?;
+            }
+        }
+        writer.finish()
+    }
+
+    /// Switch to the index file `id` written by `rewrite` and delete the old one
+    pub fn install(&mut self, id: u64) -> Result<()> {
+        let old = self.id();
+        self.load(id)?;
+        self.delta.clear();
+        if let Some(old) = old {
+            remove_table(&index_path(&self.dir_path, old))?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, id: u64) -> Result<()> {
+        self.base = Some((id, Table::open(&index_path(&self.dir_path, id))?));
+        self.cache
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner())
+            .clear();
+        Ok(())
+    }
+}
+
+pub(crate) fn index_path(dir_path: &Path, id: u64) -> PathBuf {
+    dir_path.join(format!("index_{}.idx", id))
+}
+
+/// 持有锁的线程panic时cache和统计仍然是完整的，忽略poison
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
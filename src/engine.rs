@@ -0,0 +1,55 @@
+use crate::{KvStore, LsmStore, MemStore, Result};
+
+/// Key/value operations shared by the storage engines
+pub trait KvsEngine {
+    /// Get the value of a key, `None` if the key does not exist
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Set the value of a key
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Remove a key, `KvErr::KeyNotFound` if the key does not exist
+    fn remove(&mut self, key: String) -> Result<()>;
+}
+
+impl KvsEngine for KvStore {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value).map(|_| ())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key).map(|_| ())
+    }
+}
+
+impl KvsEngine for LsmStore {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        LsmStore::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        LsmStore::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        LsmStore::remove(self, key)
+    }
+}
+
+impl KvsEngine for MemStore {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        MemStore::get(self, key)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        MemStore::set(self, key, value)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        MemStore::remove(self, key)
+    }
+}
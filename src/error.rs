@@ -1,3 +1,6 @@
+// failure_derive已经不再更新，derive(Fail)生成的impl放在匿名const中，
+// 会触发non_local_definitions；这个lint只能在模块级别关掉，所以放在这个只有错误类型的文件里
+#![allow(non_local_definitions)]
 use std::io;
 use std::result;
 use failure::Fail;
@@ -23,6 +26,63 @@ pub enum KvErr {
     
     #[fail(display = "unknown command")]
     UnknownCommand,
+
+    /// store was opened with `KvStore::open_read_only`
+    #[fail(display = "store is read-only")]
+    ReadOnly,
+
+    /// another process holds the store directory lock
+    #[fail(display = "store is locked by another process")]
+    Locked,
+
+    /// the store manifest can not be used
+    #[fail(display = "invalid manifest: {}", _0)]
+    InvalidManifest(String),
+
+    /// a data file contains an invalid record
+    #[fail(display = "corrupted data: {}", _0)]
+    Corrupted(String),
+
+    /// wrong or missing encryption key, or a tampered record
+    #[fail(display = "can not decrypt data, wrong key or tampered record")]
+    Decryption,
+
+    /// the options passed to `open_with` are invalid
+    #[fail(display = "invalid options: {}", _0)]
+    InvalidOptions(String),
+
+    /// no column family with this name
+    #[fail(display = "unknown column family {}", _0)]
+    UnknownFamily(String),
+
+    /// a column family with this name already exists
+    #[fail(display = "column family {} already exists", _0)]
+    FamilyExists(String),
+
+    /// the value of the key is not a decimal integer
+    #[fail(display = "value of key {} is not an integer", _0)]
+    NotAnInteger(String),
+
+    /// the new value of the key does not fit in an `i64`
+    #[fail(display = "value of key {} would overflow", _0)]
+    IntegerOverflow(String),
+
+    /// keys of the default column family can not start with `\0`
+    #[fail(display = "key {:?} starts with \\0, which is reserved for column families", _0)]
+    ReservedKey(String),
+
+    /// `KvStore::watch_from` can not resume before this sequence number, compaction has
+    /// dropped a removal written after it
+    #[fail(display = "removals up to sequence number {} were dropped by compaction", _0)]
+    Compacted(u64),
+
+    /// merge operands are written or read without `Options::merge_operator`
+    #[fail(display = "no merge operator")]
+    NoMergeOperator,
+
+    /// error message sent back by the server
+    #[fail(display = "{}", _0)]
+    StringError(String),
 }
 
 
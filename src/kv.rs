@@ -1,243 +1,1987 @@
-use serde_json::Deserializer;
-
-use crate::{error::KvErr, error::Result, Commands};
-use std::collections::HashMap;
-use std::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
+use crate::batch::BatchOp;
+use crate::cache::ValueCache;
+use crate::disk_index::{index_path, DiskIndex, Slot};
+use crate::manifest::{FamilyConfig, IndexCheckpoint, Manifest};
+use crate::record::{Codec, Delta, Record, RecordMeta};
+use crate::watch::Watchers;
+use crate::{
+    error::KvErr, error::Result, BloomStats, FamilyOptions, FileStats, IndexMode, MergeOperator,
+    Options, Stats, ValueReader, WatchEvent, WriteBatch,
+};
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions, TryLockError};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 const REDUNDAN_DATA_LIMIT: u64 = 1024;
+/// a data file is merged once this fraction of its bytes is garbage
+const GARBAGE_RATIO: f64 = 0.5;
+/// the active file and merge outputs are rotated once they grow past this size
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+/// writes kept in memory by the disk index before it is checkpointed
+const INDEX_DELTA_LIMIT: usize = 4096;
+/// an append writes the whole value once the chain of the key has this many earlier records
+const MAX_CHAIN_RECORDS: u32 = 32;
+const LOCK_FILE: &str = "LOCK";
+const ENGINE: &str = "kvs";
 /// KvStore main data structure
 pub struct KvStore {
-    store: HashMap<String, KvEntry>,
+    index: Index,
     current_file_id: u64,
     dir_path: PathBuf,
     current_file_offset: u64,
-    redundant_data_sz: u64,
+    /// live data files of the store, the last one is the active file
+    manifest: Manifest,
+    options: Options,
+    codec: Codec,
+    cache: ValueCache,
+    /// memory maps of immutable data files, used when `Options::mmap_reads` is set
+    mmaps: HashMap<u64, Mmap>,
+    /// end offset of every data file replayed so far, used by `refresh`
+    replayed: HashMap<u64, u64>,
+    /// exclusive lock on the directory held by a writable store, released on drop
+    _lock: Option<File>,
+    /// automatic compaction is paused by `pause_compaction`
+    compaction_paused: bool,
+    /// sequence number of the last write
+    last_seq: u64,
+    watchers: Watchers,
 }
 
-#[derive(Debug)]
+// 读只需要`&self`，store可以放在RwLock之类的锁中在线程之间共享
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<KvStore>();
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
 /// Bitcask map entry struct
-struct KvEntry {
+pub(crate) struct KvEntry {
     file_id: u64,
     value_sz: u64,
     value_pos: u64,
+    /// unix time in milliseconds after which the value is gone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+    /// sequence number of the record, `None` for records written before sequence numbers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    /// set when the record is a delta record, the earlier records of its chain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chain: Option<Chain>,
+}
+
+/// earlier records of a chain of delta records, all of them are in the data file of the entry
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub(crate) struct Chain {
+    records: u32,
+    bytes: u64,
+}
+
+impl KvEntry {
+    fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= now_millis())
+    }
+
+    /// 这条记录和它所在的delta链一共占用的字节数
+    fn chain_sz(&self) -> u64 {
+        self.value_sz + self.chain.map_or(0, |chain| chain.bytes)
+    }
+
+    /// 接在这条记录后面的delta记录，链上的记录数和字节数累加到它的entry中
+    fn chained(&self, next: KvEntry) -> KvEntry {
+        KvEntry {
+            chain: Some(Chain {
+                records: self.chain.map_or(0, |chain| chain.records) + 1,
+                bytes: self.chain_sz(),
+            }),
+            ..next
+        }
+    }
+}
+
+/// live and garbage bytes of a data file
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub(crate) struct FileUsage {
+    total: u64,
+    dead: u64,
+    /// live values with an expiry, by the second they have all expired at
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    expiring: BTreeMap<u64, Expiring>,
+}
+
+/// values of a data file expiring in the same second
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize)]
+pub(crate) struct Expiring {
+    keys: u64,
+    bytes: u64,
+}
+
+impl FileUsage {
+    /// 记录一个有过期时间的value，按秒归类，过期之后就和垃圾一样可以被压缩回收
+    fn track(&mut self, entry: &KvEntry) {
+        if let Some(expires) = entry.expires {
+            let expiring = self.expiring.entry(expiry_second(expires)).or_default();
+            expiring.keys += 1;
+            expiring.bytes += entry.value_sz;
+        }
+    }
+
+    /// value被覆盖、删除或者丢弃，不再等着过期
+    fn untrack(&mut self, entry: &KvEntry) {
+        if let Some(expires) = entry.expires {
+            let second = expiry_second(expires);
+            if let Some(expiring) = self.expiring.get_mut(&second) {
+                expiring.keys -= 1;
+                expiring.bytes -= entry.value_sz;
+                if expiring.keys == 0 {
+                    self.expiring.remove(&second);
+                }
+            }
+        }
+    }
+
+    /// 到`now`为止全部过期的value
+    fn expired(&self, now: u64) -> Expiring {
+        self.expiring
+            .range(..=now)
+            .fold(Expiring::default(), |sum, (_, expiring)| Expiring {
+                keys: sum.keys + expiring.keys,
+                bytes: sum.bytes + expiring.bytes,
+            })
+    }
+
+    /// 压缩可以回收的字节数，垃圾加上已经过期的value
+    fn reclaimable(&self, now: u64) -> u64 {
+        self.dead + self.expired(now).bytes
+    }
+}
+
+/// index rebuilt from the data files
+struct Index {
+    keys: Keys,
+    file_stats: BTreeMap<u64, FileUsage>,
+}
+
+/// location of the latest record of every key, see `IndexMode`
+enum Keys {
+    Memory {
+        entries: HashMap<String, KvEntry>,
+        /// location of the latest `Rm` record of every removed key
+        tombstones: HashMap<String, KvEntry>,
+    },
+    Disk {
+        index: DiskIndex,
+        live_keys: u64,
+    },
+}
+
+/// 压缩移动了位置的记录，manifest提交之后才更新到index中
+enum Relocation {
+    Memory {
+        /// in the iteration order of the entries, `None` drops the key
+        /// and `Slot::Dead` replaces the value by a tombstone
+        entries: Vec<Option<Slot>>,
+        tombstones: Vec<(String, Option<KvEntry>)>,
+    },
+    /// id of the rewritten index file
+    Disk(u64),
+}
+
+impl Index {
+    /// 磁盘索引从manifest中的checkpoint打开，checkpoint之后的记录还需要回放
+    fn open(dir_path: &Path, manifest: &Manifest, options: &Options) -> Result<Self> {
+        match options.index_mode {
+            IndexMode::Memory => Ok(Index {
+                keys: Keys::Memory {
+                    entries: HashMap::new(),
+                    tombstones: HashMap::new(),
+                },
+                file_stats: BTreeMap::new(),
+            }),
+            IndexMode::Disk { cache_pages } => {
+                let checkpoint = manifest.index.as_ref();
+                let index = DiskIndex::open(
+                    dir_path,
+                    checkpoint.map(|cp| cp.id),
+                    cache_pages,
+                    options.fp_rate()?,
+                )?;
+                Ok(Index {
+                    keys: Keys::Disk {
+                        index,
+                        live_keys: checkpoint.map_or(0, |cp| cp.live_keys),
+                    },
+                    file_stats: checkpoint
+                        .map(|cp| cp.file_stats.clone())
+                        .unwrap_or_default(),
+                })
+            }
+        }
+    }
+
+    /// 过期的value和不存在一样
+    fn get(&self, key: &str) -> Result<Option<KvEntry>> {
+        let entry = match &self.keys {
+            Keys::Memory { entries, .. } => entries.get(key).copied(),
+            Keys::Disk { index, .. } => match index.get(key)? {
+                Some(Slot::Live(entry)) => Some(entry),
+                _ => None,
+            },
+        };
+        Ok(entry.filter(|entry| !entry.is_expired()))
+    }
+
+    /// 记录一条Set，key之前的记录和tombstone都变成了垃圾
+    fn insert(&mut self, key: String, entry: KvEntry) -> Result<()> {
+        let usage = self.file_stats.entry(entry.file_id).or_default();
+        usage.total += entry.value_sz;
+        usage.track(&entry);
+        match &mut self.keys {
+            Keys::Memory {
+                entries,
+                tombstones,
+            } => {
+                if let Some(old) = tombstones.remove(&key) {
+                    Self::mark_dead(&mut self.file_stats, &old);
+                }
+                if let Some(old) = entries.insert(key, entry) {
+                    Self::mark_dead(&mut self.file_stats, &old);
+                }
+            }
+            Keys::Disk { index, live_keys } => {
+                match index.get(&key)? {
+                    Some(Slot::Live(old)) => Self::mark_dead(&mut self.file_stats, &old),
+                    Some(Slot::Dead(old)) => {
+                        Self::mark_dead(&mut self.file_stats, &old);
+                        *live_keys += 1;
+                    }
+                    None => *live_keys += 1,
+                }
+                index.put(key, Slot::Live(entry));
+            }
+        }
+        Ok(())
+    }
+
+    /// 记录一条接在key最新记录后面的delta记录，之前的记录成为链的一部分，仍然有效
+    fn extend(&mut self, key: String, entry: KvEntry) {
+        self.file_stats.entry(entry.file_id).or_default().total += entry.value_sz;
+        match &mut self.keys {
+            Keys::Memory { entries, .. } => {
+                entries.insert(key, entry);
+            }
+            Keys::Disk { index, .. } => index.put(key, Slot::Live(entry)),
+        }
+    }
+
+    /// 记录一条Rm，在被丢弃之前tombstone本身算作有效数据
+    fn remove(&mut self, key: String, tombstone: KvEntry) -> Result<()> {
+        self.file_stats.entry(tombstone.file_id).or_default().total += tombstone.value_sz;
+        match &mut self.keys {
+            Keys::Memory {
+                entries,
+                tombstones,
+            } => {
+                if let Some(old) = entries.remove(&key) {
+                    Self::mark_dead(&mut self.file_stats, &old);
+                }
+                if let Some(old) = tombstones.insert(key, tombstone) {
+                    Self::mark_dead(&mut self.file_stats, &old);
+                }
+            }
+            Keys::Disk { index, live_keys } => {
+                match index.get(&key)? {
+                    Some(Slot::Live(old)) => {
+                        Self::mark_dead(&mut self.file_stats, &old);
+                        *live_keys -= 1;
+                    }
+                    Some(Slot::Dead(old)) => Self::mark_dead(&mut self.file_stats, &old),
+                    None => {}
+                }
+                index.put(key, Slot::Dead(tombstone));
+            }
+        }
+        Ok(())
+    }
+
+    /// 被relocate丢弃的slot变成垃圾，磁盘索引的live key计数随之减少，
+    /// 内存索引在apply时删除它们
+    fn discard(&mut self, slots: &[Slot]) {
+        for slot in slots {
+            match slot {
+                Slot::Live(entry) => {
+                    Self::mark_dead(&mut self.file_stats, entry);
+                    if let Keys::Disk { live_keys, .. } = &mut self.keys {
+                        *live_keys -= 1;
+                    }
+                }
+                Slot::Dead(tombstone) => Self::mark_dead(&mut self.file_stats, tombstone),
+            }
+        }
+    }
+
+    fn mark_dead(file_stats: &mut BTreeMap<u64, FileUsage>, entry: &KvEntry) {
+        if let Some(stats) = file_stats.get_mut(&entry.file_id) {
+            stats.dead += entry.chain_sz();
+            stats.untrack(entry);
+        }
+    }
+
+    /// 垃圾和已经过期的value的字节数
+    fn dead_bytes(&self, now: u64) -> u64 {
+        self.file_stats
+            .values()
+            .map(|stats| stats.reclaimable(now))
+            .sum()
+    }
+
+    /// 包括已经过期、还没有被压缩丢弃的key
+    fn live_keys(&self) -> u64 {
+        match &self.keys {
+            Keys::Memory { entries, .. } => entries.len() as u64,
+            Keys::Disk { live_keys, .. } => *live_keys,
+        }
+    }
+
+    fn bloom_stats(&self) -> BloomStats {
+        match &self.keys {
+            Keys::Memory { .. } => BloomStats::default(),
+            Keys::Disk { index, .. } => index.bloom_stats(),
+        }
+    }
+
+    /// id of the index file in use by the disk index
+    fn disk_id(&self) -> Option<u64> {
+        match &self.keys {
+            Keys::Memory { .. } => None,
+            Keys::Disk { index, .. } => index.id(),
+        }
+    }
+
+    fn delta_len(&self) -> usize {
+        match &self.keys {
+            Keys::Memory { .. } => 0,
+            Keys::Disk { index, .. } => index.delta_len(),
+        }
+    }
+
+    /// 把每个key最新的记录交给copy，返回它的新位置，copy返回None时丢弃这个key。
+    /// 磁盘索引写到新的索引文件`index_id`中
+    fn relocate(
+        &self,
+        index_id: u64,
+        mut copy: impl FnMut(&str, Slot) -> Result<Option<Slot>>,
+    ) -> Result<Relocation> {
+        match &self.keys {
+            Keys::Memory {
+                entries,
+                tombstones,
+            } => {
+                let mut moved_entries = Vec::with_capacity(entries.len());
+                for (key, entry) in entries {
+                    moved_entries.push(copy(key, Slot::Live(*entry))?);
+                }
+                let mut moved_tombstones = Vec::new();
+                for (key, tombstone) in tombstones {
+                    let moved = copy(key, Slot::Dead(*tombstone))?.map(|slot| match slot {
+                        Slot::Live(moved) | Slot::Dead(moved) => moved,
+                    });
+                    if moved != Some(*tombstone) {
+                        moved_tombstones.push((key.clone(), moved));
+                    }
+                }
+                Ok(Relocation::Memory {
+                    entries: moved_entries,
+                    tombstones: moved_tombstones,
+                })
+            }
+            Keys::Disk { index, .. } => {
+                index.rewrite(index_id, copy)?;
+                Ok(Relocation::Disk(index_id))
+            }
+        }
+    }
+
+    fn apply(&mut self, relocation: Relocation) -> Result<()> {
+        match (&mut self.keys, relocation) {
+            (
+                Keys::Memory {
+                    entries,
+                    tombstones,
+                },
+                Relocation::Memory {
+                    entries: moved_entries,
+                    tombstones: moved_tombstones,
+                },
+            ) => {
+                // 写入的顺序和遍历的顺序一致，index在这期间没有修改
+                let mut dropped = Vec::new();
+                for ((key, entry), moved) in entries.iter_mut().zip(moved_entries) {
+                    match moved {
+                        Some(Slot::Live(moved)) => *entry = moved,
+                        Some(Slot::Dead(moved)) => {
+                            tombstones.insert(key.clone(), moved);
+                            dropped.push(key.clone());
+                        }
+                        None => dropped.push(key.clone()),
+                    }
+                }
+                for key in dropped {
+                    entries.remove(&key);
+                }
+                for (key, moved) in moved_tombstones {
+                    match moved {
+                        Some(moved) => tombstones.insert(key, moved),
+                        None => tombstones.remove(&key),
+                    };
+                }
+            }
+            (Keys::Disk { index, .. }, Relocation::Disk(index_id)) => index.install(index_id)?,
+            _ => unreachable!("relocation of another index mode"),
+        }
+        Ok(())
+    }
+
+    /// checkpoint of the disk index after `install`, covering the records before `offset`
+    fn checkpoint(&self, id: u64, file_id: u64, offset: u64) -> IndexCheckpoint {
+        IndexCheckpoint {
+            id,
+            file_id,
+            offset,
+            live_keys: self.live_keys(),
+            file_stats: self.file_stats.clone(),
+        }
+    }
 }
 
 /// impl new get set remove method
 impl KvStore {
     /// Open the KvStore at a given path. Return the KvStore
+    ///
+    /// Only the data files listed in the manifest are read. Any other `store_file_*.txt`,
+    /// such as a file left behind by a crash during compaction, is deleted when the store
+    /// is opened for writing, so its id can be reused safely.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with(path, Options::default())
+    }
+
+    /// Open the KvStore at a given path in read-only mode.
+    /// The store never writes, deletes or compacts files, so it can be opened
+    /// while another process is writing to the same directory.
+    /// Mutating calls return `KvErr::ReadOnly`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        Self::open_with(path, options)
+    }
+
+    /// Open the KvStore at a given path with the given options
+    pub fn open_with(path: impl Into<PathBuf>, options: Options) -> Result<KvStore> {
         let dir_path = path.into();
+        if options.read_only {
+            let manifest = Manifest::new(ENGINE);
+            let mut kv = KvStore {
+                index: Index::open(&dir_path, &manifest, &options)?,
+                current_file_id: 0,
+                dir_path,
+                current_file_offset: 0,
+                manifest,
+                codec: Codec::new(&options),
+                cache: ValueCache::new(options.value_cache_bytes),
+                options,
+                mmaps: HashMap::new(),
+                replayed: HashMap::new(),
+                _lock: None,
+                compaction_paused: false,
+                last_seq: 0,
+                watchers: Watchers::default(),
+            };
+            kv.refresh()?;
+            return Ok(kv);
+        }
         create_dir_all(&dir_path)?;
-        let mut store = HashMap::new();
-        let (current_file_id, current_file_offset, redundant_data_sz) =
-            Self::recover(&dir_path, &mut store)?;
-        if current_file_id == 0 {
-            let new_file_name = dir_path.join(format!("store_file_{}.txt", current_file_id));
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&new_file_name)?;
+        let lock = Self::lock_dir(&dir_path)?;
+        let codec = Codec::new(&options);
+        let mut manifest = match Manifest::load(&dir_path, ENGINE)? {
+            Some(manifest) => manifest,
+            // 旧版本的目录没有manifest，根据目录中的data files生成一个
+            None => {
+                let files = Self::find_dir_data_files(&dir_path)?;
+                let manifest = Manifest {
+                    next_file_id: files.last().map_or(0, |id| id + 1),
+                    files,
+                    ..Manifest::new(ENGINE)
+                };
+                manifest.save(&dir_path)?;
+                manifest
+            }
+        };
+        match &manifest.key_check {
+            Some(key_check) => codec.verify_key(key_check)?,
+            // 有key时不接受没有加密的记录，已经写入的明文数据会读不出来
+            None if codec.encrypted() && Self::has_data(&dir_path, &manifest)? => {
+                return Err(KvErr::InvalidOptions(
+                    "store holds unencrypted data, it cannot be opened with an encryption key"
+                        .to_owned(),
+                ));
+            }
+            None => {
+                manifest.key_check = codec.key_check()?;
+                if manifest.key_check.is_some() {
+                    manifest.save(&dir_path)?;
+                }
+            }
         }
+        // 压缩需要operator合并operand，只读的handle在refresh里检查
+        match (&manifest.merge_operator, &options.merge_operator) {
+            (Some(name), Some(operator)) if name != operator.name() => {
+                return Err(KvErr::InvalidOptions(format!(
+                    "store was written with merge operator {}",
+                    name
+                )));
+            }
+            (Some(name), None) => {
+                return Err(KvErr::InvalidOptions(format!(
+                    "store has merge operands, open it with merge operator {}",
+                    name
+                )));
+            }
+            _ => {}
+        }
+        if options.index_mode == IndexMode::Memory && manifest.index.is_some() {
+            // 内存模式不维护磁盘索引，丢掉旧的checkpoint，切回磁盘模式时重新建立
+            manifest.index = None;
+            manifest.save(&dir_path)?;
+        }
+        Self::remove_stale_files(&dir_path, &manifest)?;
+        let index = Index::open(&dir_path, &manifest, &options)?;
         let mut kv = KvStore {
-            store,
-            current_file_id,
+            index,
+            current_file_id: manifest.files.last().copied().unwrap_or(0),
             dir_path,
-            current_file_offset,
-            redundant_data_sz,
+            current_file_offset: 0,
+            manifest,
+            codec,
+            cache: ValueCache::new(options.value_cache_bytes),
+            options,
+            mmaps: HashMap::new(),
+            replayed: HashMap::new(),
+            _lock: Some(lock),
+            compaction_paused: false,
+            last_seq: 0,
+            watchers: Watchers::default(),
         };
-        if redundant_data_sz > REDUNDAN_DATA_LIMIT {
-            kv.compact()?
+        kv.recover()?;
+        if kv.manifest.files.is_empty() {
+            kv.create_new_file()?;
         }
+        kv.compact()?;
         Ok(kv)
     }
 
+    /// Pick up records appended by the writer since the store was opened or last refreshed.
+    /// Only records after the last replayed offset of each file are read, unless the
+    /// writer compacted the store meanwhile, in which case the index is rebuilt.
+    /// A no-op for a writable store, whose index is always up to date.
+    ///
+    /// Watchers of the handle are sent the writes picked up, like `watch_from` would
+    /// send them.
+    pub fn refresh(&mut self) -> Result<()> {
+        if !self.options.read_only {
+            return Ok(());
+        }
+        // seq大于delivered的写入是上次refresh之后的写入，通知给watchers。
+        // 重建索引时磁盘索引的checkpoint可能已经包含了一部分新的写入，
+        // 所以事件从上次回放到的位置开始读，而不是从这次回放的位置开始
+        let delivered = self.last_seq;
+        let scanned = self.replayed.clone();
+        let mut events = Vec::new();
+        let data_files = match Manifest::load(&self.dir_path, ENGINE)? {
+            Some(manifest) => {
+                if let Some(key_check) = &manifest.key_check {
+                    self.codec.verify_key(key_check)?;
+                }
+                // 只读的handle不压缩，没有operator时读到operand才报错，但不能用另一个operator合并
+                if let (Some(name), Some(operator)) =
+                    (&manifest.merge_operator, &self.options.merge_operator)
+                {
+                    if name != operator.name() {
+                        return Err(KvErr::InvalidOptions(format!(
+                            "store was written with merge operator {}",
+                            name
+                        )));
+                    }
+                }
+                self.last_seq = self.last_seq.max(manifest.last_sequence);
+                self.manifest = manifest;
+                self.manifest.files.clone()
+            }
+            None => Self::find_dir_data_files(&self.dir_path)?,
+        };
+        // 磁盘索引在writer做了新的checkpoint之后从新的索引文件重新打开
+        let new_checkpoint = matches!(self.options.index_mode, IndexMode::Disk { .. })
+            && self.manifest.index.as_ref().map(|cp| cp.id) != self.index.disk_id();
+        if new_checkpoint || self.replayed.keys().any(|data| !data_files.contains(data)) {
+            self.index = Index::open(&self.dir_path, &self.manifest, &self.options)?;
+            self.replayed.clear();
+            self.mmaps.clear();
+            self.cache.clear();
+        }
+        for data in data_files.iter().copied() {
+            let offset = match self.replayed.get(&data) {
+                Some(offset) => Some(*offset),
+                None => self.replay_offset(data),
+            };
+            // checkpoint已经包含的文件是不可变的，事件读到文件末尾
+            let end_offset = match offset {
+                Some(offset) => self.replay(data, offset, true)?,
+                None => u64::MAX,
+            };
+            let from = scanned.get(&data).copied().unwrap_or(0);
+            if !self.watchers.is_empty() && end_offset > from {
+                events.extend(self.read_events(data, from, end_offset, delivered, |key| {
+                    self.watchers.watches(key)
+                })?);
+            }
+            let offset = match offset {
+                Some(offset) => offset,
+                None => continue,
+            };
+            if end_offset != offset {
+                // writer写入的key不知道是哪些，整个缓存失效
+                self.cache.clear();
+            }
+            self.replayed.insert(data, end_offset);
+            self.current_file_id = data;
+            self.current_file_offset = end_offset;
+        }
+        // 压缩后的文件中记录不再按seq排列
+        events.sort_by_key(WatchEvent::seq);
+        for event in events {
+            let key = event.key().to_owned();
+            self.watchers.notify(&key, || event);
+        }
+        Ok(())
+    }
+
+    /// Return live and garbage statistics of the store and its compaction history
+    /// Values past their TTL count as dead until compaction drops them.
+    pub fn stats(&self) -> Stats {
+        let now = now_millis();
+        let files: Vec<FileStats> = self
+            .index
+            .file_stats
+            .iter()
+            .map(|(data, usage)| FileStats {
+                file_id: *data,
+                total_bytes: usage.total,
+                dead_bytes: usage.reclaimable(now),
+            })
+            .collect();
+        let dead_bytes = files.iter().map(|file| file.dead_bytes).sum();
+        let total_bytes: u64 = files.iter().map(|file| file.total_bytes).sum();
+        let expired_keys: u64 = self
+            .index
+            .file_stats
+            .values()
+            .map(|usage| usage.expired(now).keys)
+            .sum();
+        Stats {
+            live_keys: self.index.live_keys().saturating_sub(expired_keys),
+            live_bytes: total_bytes - dead_bytes,
+            dead_bytes,
+            files,
+            compaction: self.manifest.compaction.clone(),
+            bloom: self.index.bloom_stats(),
+            cache: self.cache.stats(),
+        }
+    }
+
+    /// Compact the whole store right away, even if compaction is paused.
+    /// Every data file is merged, so all garbage and tombstones are dropped.
+    pub fn compact_now(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.merge_files(self.manifest.files.clone())
+    }
+
+    /// Stop triggering compaction automatically on writes,
+    /// e.g. during peak load. `compact_now` still works.
+    pub fn pause_compaction(&mut self) {
+        self.compaction_paused = true;
+    }
+
+    /// Trigger compaction automatically again, a compaction due meanwhile runs
+    /// on the next write
+    pub fn resume_compaction(&mut self) {
+        self.compaction_paused = false;
+    }
+
     /// Get the string value of a string key. If the key does not exist, return None.
     /// Return an error if the value is not read successfully.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.store.get(&key) {
-            Some(t) => {
-                let reader_path = self.dir_path.join(format!("store_file_{}.txt", t.file_id));
-                let file = OpenOptions::new().read(true).open(&reader_path)?;
-                let mut buf_reader = BufReader::new(file);
-                buf_reader.seek(SeekFrom::Start(t.value_pos))?;
-                let read_file_with_cap = buf_reader.take(t.value_sz);
-                // TODO: serde_json::from_reader slower method than from_str or simliar other method
-                if let Some(Commands::Set { key: _, value }) =
-                    serde_json::from_reader(read_file_with_cap)?
-                {
-                    Ok(Some(value))
-                } else {
-                    Err(KvErr::UnknownCommand)
-                }
+        check_key(&key)?;
+        self.read_value(key)
+    }
+
+    /// 按index中的key读取value，缓存也以index中的key为准。
+    /// 会过期的value不放入缓存，否则过期之后仍然可以从缓存中读到
+    fn read_value(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.cache.get(&key) {
+            return Ok(Some(value));
+        }
+        let entry = match self.index.get(&key)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let (file_id, value_pos, value_sz) = (entry.file_id, entry.value_pos, entry.value_sz);
+        let value = if entry.chain.is_some() {
+            Self::read_chain(
+                &self.dir_path,
+                &self.codec,
+                self.options.merge_operator.as_deref(),
+                &key,
+                &entry,
+            )?
+        } else if self.mmap(file_id)?.is_some() {
+            // 直接从映射的内存中解码，没有压缩的value只在最后拷贝一次
+            let mmap = &self.mmaps[&file_id];
+            let record = &mmap[value_pos as usize..(value_pos + value_sz) as usize];
+            Record::decode_string(record, &self.codec)?
+        } else {
+            let reader_path = self.dir_path.join(format!("store_file_{}.txt", file_id));
+            let file = OpenOptions::new().read(true).open(&reader_path)?;
+            let mut buf_reader = BufReader::new(file);
+            buf_reader.seek(SeekFrom::Start(value_pos))?;
+            let mut record = vec![0; value_sz as usize];
+            buf_reader.read_exact(&mut record)?;
+            Record::decode_string(&record, &self.codec)?
+        };
+        if entry.expires.is_none() {
+            self.cache.insert(key, value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    /// Get a reader over the value of a key. If the key does not exist, return None.
+    /// Unlike `get`, a value written by `set_reader` is never loaded into memory as a whole.
+    pub fn get_reader(&self, key: String) -> Result<Option<ValueReader>> {
+        check_key(&key)?;
+        let entry = match self.index.get(&key)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if entry.chain.is_some() {
+            let value = Self::read_chain(
+                &self.dir_path,
+                &self.codec,
+                self.options.merge_operator.as_deref(),
+                &key,
+                &entry,
+            )?;
+            return Ok(Some(ValueReader::memory(value.into_bytes())));
+        }
+        let file_path = self
+            .dir_path
+            .join(format!("store_file_{}.txt", entry.file_id));
+        let mut reader = BufReader::new(File::open(&file_path)?);
+        reader.seek(SeekFrom::Start(entry.value_pos))?;
+        Record::open_value(reader, &self.codec).map(Some)
+    }
+
+    /// Read the bytes of the value of a key in `range`, cut at the end of the value.
+    /// If the key does not exist, return None.
+    /// For values written by `set_reader` and values stored neither compressed nor encrypted,
+    /// the bytes before the range are skipped by seeking, an encrypted value written by
+    /// `set_reader` is decrypted from the chunk the range starts in.
+    /// Compressed values, values encrypted as a whole and values with append or merge
+    /// records are decoded in memory first.
+    pub fn get_range(&self, key: String, range: Range<u64>) -> Result<Option<Vec<u8>>> {
+        let mut reader = match self.get_reader(key)? {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        reader.skip(range.start)?;
+        let mut bytes = Vec::new();
+        reader
+            .take(range.end.saturating_sub(range.start))
+            .read_to_end(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    /// Get the values of many keys at once, in the same order as `keys`.
+    /// Lookups are grouped by data file and sorted by position, so each file is
+    /// opened once and read front to back.
+    pub fn get_many(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let mut entries = Vec::new();
+        for (idx, key) in keys.iter().enumerate() {
+            check_key(key)?;
+            if let Some(entry) = self.index.get(key)? {
+                entries.push((idx, entry));
             }
-            None => Ok(None),
         }
+        entries.sort_by_key(|(_, entry)| (entry.file_id, entry.value_pos));
+
+        let mut reader: Option<(u64, BufReader<File>, u64)> = None;
+        let mut buf = Vec::new();
+        for (idx, entry) in entries {
+            if entry.chain.is_some() {
+                values[idx] = Some(Self::read_chain(
+                    &self.dir_path,
+                    &self.codec,
+                    self.options.merge_operator.as_deref(),
+                    &keys[idx],
+                    &entry,
+                )?);
+                continue;
+            }
+            let (_, buf_reader, pos) = match reader {
+                Some((file_id, _, _)) if file_id == entry.file_id => reader.as_mut().unwrap(),
+                _ => {
+                    let file_path = self
+                        .dir_path
+                        .join(format!("store_file_{}.txt", entry.file_id));
+                    reader.insert((entry.file_id, BufReader::new(File::open(&file_path)?), 0))
+                }
+            };
+            // 同一个文件内按位置顺序读取，相对seek可以复用BufReader的缓冲
+            buf_reader.seek_relative(entry.value_pos as i64 - *pos as i64)?;
+            buf.resize(entry.value_sz as usize, 0);
+            buf_reader.read_exact(&mut buf)?;
+            *pos = entry.value_pos + entry.value_sz;
+            values[idx] = Some(Record::decode_string(&buf, &self.codec)?);
+        }
+        Ok(values)
     }
-    /// Set the value of a string key to a string.
+
+    /// Set the value of a string key to a string, returns the sequence number of the write.
     /// Return an error if the value is not written successfully.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Commands::Set {
-            key: key.clone(),
-            value,
+    pub fn set(&mut self, key: String, value: String) -> Result<u64> {
+        self.check_writable()?;
+        check_key(&key)?;
+        let seq = self.next_seq();
+        let mut series_data = Vec::new();
+        let meta = RecordMeta {
+            seq: Some(seq),
+            ..RecordMeta::default()
         };
-        let series_data = serde_json::to_string(&cmd)?;
+        Record::encode_put(&key, value.as_bytes(), meta, &self.codec, &mut series_data)?;
         let new_file_name = self
             .dir_path
             .join(format!("store_file_{}.txt", self.current_file_id));
         let mut file = OpenOptions::new().append(true).open(&new_file_name)?;
-        let len = file.write(series_data.as_bytes())?;
-        assert_ne!(len, 0);
+        file.write_all(&series_data)?;
+        let len = series_data.len() as u64;
         let offset = self.current_file_offset;
-        self.current_file_offset += len as u64;
+        self.current_file_offset += len;
         let entry = KvEntry {
             file_id: self.current_file_id,
             value_pos: offset,
-            value_sz: len as u64,
+            value_sz: len,
+            expires: None,
+            seq: Some(seq),
+            chain: None,
+        };
+        self.cache.invalidate(&key);
+        self.watchers.notify(&key, || WatchEvent::Set {
+            seq,
+            key: key.clone(),
+            value,
+        });
+        self.index.insert(key, entry)?;
+        self.after_write()?;
+        Ok(seq)
+    }
+
+    /// Set the value of a key to `len` bytes read from `reader`.
+    /// The value is copied to the data file in chunks without being held in memory,
+    /// it is stored uncompressed. Nothing is written if `reader` has less than `len` bytes.
+    /// Watchers are sent a `WatchEvent::SetStream` without the value.
+    /// Returns the sequence number of the write.
+    pub fn set_reader(&mut self, key: String, reader: impl Read, len: u64) -> Result<u64> {
+        self.check_writable()?;
+        check_key(&key)?;
+        let seq = self.next_seq();
+        let meta = RecordMeta {
+            seq: Some(seq),
+            ..RecordMeta::default()
         };
-        self.redundant_data_sz += self
-            .store
-            .insert(key, entry)
-            .map(|entry| entry.value_sz)
-            .unwrap_or(0);
-        if self.redundant_data_sz > REDUNDAN_DATA_LIMIT {
-            self.compact()?
+        let file_path = self
+            .dir_path
+            .join(format!("store_file_{}.txt", self.current_file_id));
+        let mut writer = BufWriter::new(OpenOptions::new().append(true).open(&file_path)?);
+        let written = Record::write_stream(&key, reader, len, meta, &self.codec, &mut writer)
+            .and_then(|len| Ok(writer.flush().map(|_| len)?));
+        let len = match written {
+            Ok(len) => len,
+            Err(err) => {
+                // 丢掉没写完的记录，避免后续记录追加在不完整的记录后面
+                let (file, _) = writer.into_parts();
+                file.set_len(self.current_file_offset)?;
+                return Err(err);
+            }
+        };
+        let entry = KvEntry {
+            file_id: self.current_file_id,
+            value_pos: self.current_file_offset,
+            value_sz: len,
+            expires: None,
+            seq: meta.seq,
+            chain: None,
+        };
+        self.current_file_offset += len;
+        self.cache.invalidate(&key);
+        self.watchers.notify(&key, || WatchEvent::SetStream {
+            seq,
+            key: key.clone(),
+        });
+        self.index.insert(key, entry)?;
+        self.after_write()?;
+        Ok(seq)
+    }
+
+    /// Set many key/value pairs at once.
+    /// All records are appended to the active data file with a single write.
+    /// Returns the sequence numbers of the writes, in the order of `pairs`.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<u64>> {
+        self.check_writable()?;
+        for (key, _) in &pairs {
+            check_key(key)?;
         }
-        Ok(())
+        let mut series_data = Vec::new();
+        let mut entries = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let offset = self.current_file_offset + series_data.len() as u64;
+            let seq = self.next_seq();
+            let meta = RecordMeta {
+                seq: Some(seq),
+                ..RecordMeta::default()
+            };
+            Record::encode_put(&key, value.as_bytes(), meta, &self.codec, &mut series_data)?;
+            let entry = KvEntry {
+                file_id: self.current_file_id,
+                value_pos: offset,
+                value_sz: self.current_file_offset + series_data.len() as u64 - offset,
+                expires: None,
+                seq: Some(seq),
+                chain: None,
+            };
+            entries.push((key, entry, seq, value));
+        }
+        let new_file_name = self
+            .dir_path
+            .join(format!("store_file_{}.txt", self.current_file_id));
+        let mut file = OpenOptions::new().append(true).open(&new_file_name)?;
+        file.write_all(&series_data)?;
+        self.current_file_offset += series_data.len() as u64;
+        let mut seqs = Vec::with_capacity(entries.len());
+        for (key, entry, seq, value) in entries {
+            self.cache.invalidate(&key);
+            self.watchers.notify(&key, || WatchEvent::Set {
+                seq,
+                key: key.clone(),
+                value,
+            });
+            self.index.insert(key, entry)?;
+            seqs.push(seq);
+        }
+        self.after_write()?;
+        Ok(seqs)
+    }
+
+    /// Append `suffix` to the value of a key, a missing key starts from an empty value.
+    /// Returns the sequence number of the write.
+    /// The suffix is written as a delta record without reading the value, reads join the
+    /// deltas and compaction merges them into one record. The first append after the active
+    /// file is rotated, and every append to a long chain of deltas, writes the whole value.
+    pub fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        self.check_writable()?;
+        check_key(&key)?;
+        self.write_delta(Delta::Append, key, suffix)
+    }
+
+    /// Log `operand` for the merge operator of the store, returns the sequence number of the
+    /// write. Like `append`, the operand is folded into the value by reads and compaction.
+    /// Returns `KvErr::NoMergeOperator` if the store was opened without `Options::merge_operator`.
+    pub fn merge(&mut self, key: String, operand: String) -> Result<u64> {
+        self.check_writable()?;
+        check_key(&key)?;
+        let name = match &self.options.merge_operator {
+            Some(operator) => operator.name().to_owned(),
+            None => return Err(KvErr::NoMergeOperator),
+        };
+        // 第一次写入merge operand时记下operator的名字，之后只能用同名的operator打开
+        if self.manifest.merge_operator.is_none() {
+            self.manifest.merge_operator = Some(name);
+            self.manifest.save(&self.dir_path)?;
+        }
+        self.write_delta(Delta::Merge, key, operand)
     }
 
-    /// Remove a given key.
+    /// 把operand写成一条delta记录，接在key的最新记录后面
+    fn write_delta(&mut self, delta: Delta, key: String, operand: String) -> Result<u64> {
+        // 链上的记录都在同一个data file中，链太长时读取要读很多条记录
+        let prev = match self.index.get(&key)? {
+            Some(head)
+                if head.file_id != self.current_file_id
+                    || head
+                        .chain
+                        .is_some_and(|chain| chain.records >= MAX_CHAIN_RECORDS) =>
+            {
+                let value = self.read_value(key.clone())?;
+                let operator = self.options.merge_operator.as_deref();
+                let value = apply_delta(operator, delta, &key, value, &operand)?;
+                return self.set(key, value);
+            }
+            head => head,
+        };
+        let seq = self.next_seq();
+        let mut series_data = Vec::new();
+        Record::encode_delta(
+            delta,
+            &key,
+            operand.as_bytes(),
+            prev.map(|head| head.value_pos),
+            seq,
+            &self.codec,
+            &mut series_data,
+        )?;
+        let file_path = self
+            .dir_path
+            .join(format!("store_file_{}.txt", self.current_file_id));
+        let mut file = OpenOptions::new().append(true).open(&file_path)?;
+        file.write_all(&series_data)?;
+        let len = series_data.len() as u64;
+        let entry = KvEntry {
+            file_id: self.current_file_id,
+            value_pos: self.current_file_offset,
+            value_sz: len,
+            expires: None,
+            seq: Some(seq),
+            chain: Some(Chain::default()),
+        };
+        self.current_file_offset += len;
+        self.cache.invalidate(&key);
+        self.watchers.notify(&key, || match delta {
+            Delta::Append => WatchEvent::Append {
+                seq,
+                key: key.clone(),
+                suffix: operand,
+            },
+            Delta::Merge => WatchEvent::Merge {
+                seq,
+                key: key.clone(),
+                operand,
+            },
+        });
+        match prev {
+            Some(head) => self.index.extend(key, head.chained(entry)),
+            None => self.index.insert(key, entry)?,
+        }
+        self.after_write()?;
+        Ok(seq)
+    }
+
+    /// Remove a given key, returns the sequence number of the write.
     /// Return an error if the key does not exist or is not removed successfully.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        match self.store.get(&key) {
-            Some(t) => {
-                let cmd = Commands::Rm { key: key.clone() };
-                let series_data = serde_json::to_string(&cmd)?;
-                let file_path = self.dir_path.join(format!("store_file_{}.txt", t.file_id));
-                let mut file = OpenOptions::new().append(true).open(&file_path)?;
-                let len = file.write(series_data.as_bytes())?;
-                self.current_file_offset += len as u64;
-                self.redundant_data_sz += len as u64;
-                self.redundant_data_sz += self
-                    .store
-                    .remove(&key)
-                    .map(|entry| entry.value_sz)
-                    .unwrap_or(0);
-
-                if self.redundant_data_sz > REDUNDAN_DATA_LIMIT {
-                    self.compact()?
-                }
-                Ok(())
+    pub fn remove(&mut self, key: String) -> Result<u64> {
+        self.check_writable()?;
+        check_key(&key)?;
+        if self.index.get(&key)?.is_none() {
+            return Err(KvErr::KeyNotFound);
+        }
+        // tombstone总是追加到active file中，它比所有旧文件中这个key的记录都新
+        let seq = self.next_seq();
+        let meta = RecordMeta {
+            seq: Some(seq),
+            ..RecordMeta::default()
+        };
+        let mut series_data = Vec::new();
+        Record::encode_rm(&key, meta, &self.codec, &mut series_data)?;
+        let file_path = self
+            .dir_path
+            .join(format!("store_file_{}.txt", self.current_file_id));
+        let mut file = OpenOptions::new().append(true).open(&file_path)?;
+        file.write_all(&series_data)?;
+        let len = series_data.len() as u64;
+        let tombstone = KvEntry {
+            file_id: self.current_file_id,
+            value_pos: self.current_file_offset,
+            value_sz: len,
+            expires: None,
+            seq: Some(seq),
+            chain: None,
+        };
+        self.current_file_offset += len;
+        self.cache.invalidate(&key);
+        self.watchers.notify(&key, || WatchEvent::Rm {
+            seq,
+            key: key.clone(),
+        });
+        self.index.remove(key, tombstone)?;
+        self.after_write()?;
+        Ok(seq)
+    }
+
+    /// Add `delta` to the integer value of a key and return the new value.
+    /// A missing key counts as 0. Returns `KvErr::NotAnInteger` if the value is not
+    /// a decimal integer and `KvErr::IntegerOverflow` if the result does not fit in an `i64`.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.update_integer(key, |current| current.checked_add(delta))
+    }
+
+    /// Subtract `delta` from the integer value of a key and return the new value, see `incr`.
+    pub fn decr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.update_integer(key, |current| current.checked_sub(delta))
+    }
+
+    /// 读出整数value，用`update`计算新的value并写回，`update`返回None表示溢出
+    fn update_integer(
+        &mut self,
+        key: String,
+        update: impl FnOnce(i64) -> Option<i64>,
+    ) -> Result<i64> {
+        self.check_writable()?;
+        // 读和写都在同一个`&mut self`中完成，同一个handle上不会交错
+        let current = match self.get(key.clone())? {
+            Some(value) => value
+                .parse::<i64>()
+                .map_err(|_| KvErr::NotAnInteger(key.clone()))?,
+            None => 0,
+        };
+        let value = update(current).ok_or_else(|| KvErr::IntegerOverflow(key.clone()))?;
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    /// Create a column family, a key space independent of the default one and of
+    /// the other families, stored in the same data files.
+    pub fn create_family(&mut self, name: &str, options: FamilyOptions) -> Result<()> {
+        self.check_writable()?;
+        if self.manifest.families.contains_key(name) {
+            return Err(KvErr::FamilyExists(name.to_owned()));
+        }
+        let config = FamilyConfig {
+            id: self.manifest.next_family_id,
+            ttl_millis: options.ttl.map(|ttl| ttl.as_millis() as u64),
+            compression: options.compression,
+        };
+        self.manifest.next_family_id += 1;
+        self.manifest.families.insert(name.to_owned(), config);
+        self.manifest.save(&self.dir_path)
+    }
+
+    /// Names of the column families, not including the default family
+    pub fn families(&self) -> Vec<String> {
+        self.manifest.families.keys().cloned().collect()
+    }
+
+    /// Drop a column family and every key in it.
+    /// The records of the family become garbage, reclaimed by compaction.
+    pub fn drop_family(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let prefix = family_key(Some(self.family(name)?.id), "");
+        let index_id = self.next_index_id();
+        let mut dropped = Vec::new();
+        let relocation = self.index.relocate(index_id, |key, slot| {
+            if key.starts_with(&prefix) {
+                dropped.push(slot);
+                Ok(None)
+            } else {
+                Ok(Some(slot))
             }
-            None => Err(KvErr::KeyNotFound),
+        })?;
+        self.index.discard(&dropped);
+        // family从manifest中删除是提交点，之后回放时它的记录都被忽略
+        self.manifest.families.remove(name);
+        if let Relocation::Disk(index_id) = relocation {
+            self.manifest.index = Some(self.index.checkpoint(
+                index_id,
+                self.current_file_id,
+                self.current_file_offset,
+            ));
         }
+        self.manifest.save(&self.dir_path)?;
+        self.index.apply(relocation)?;
+        self.cache.clear();
+        self.compact()
     }
 
-    /// 恢复流程：
-    /// 1. 读取对应文件夹下面的data files，并排序
-    /// 2. 对每个文件进行恢复，KvEntry
-    /// 3. 返回最后一个文件的编号和offset
-    fn recover(
-        dir_path: &PathBuf,
-        store: &mut HashMap<String, KvEntry>,
-    ) -> Result<(u64, u64, u64)> {
-        let data_files = Self::find_dir_data_files(&dir_path)?;
-        let mut current_file_offset = 0;
-        let mut redundant_data_sz = 0;
-        for data in &data_files {
-            let file_path = dir_path.join(format!("store_file_{}.txt", data));
-            let reader = BufReader::new(File::open(&file_path)?);
-            let mut iter = Deserializer::from_reader(reader).into_iter::<Commands>();
-            let mut before_offset = iter.byte_offset() as u64;
-            while let Some(command) = iter.next() {
-                let after_offset = iter.byte_offset() as u64;
-                assert_ne!(after_offset, before_offset);
-                match command? {
-                    Commands::Set { key, value: _ } => {
-                        redundant_data_sz += store
-                            .insert(
-                                key,
-                                KvEntry {
-                                    file_id: *data,
-                                    value_sz: after_offset - before_offset,
-                                    value_pos: before_offset,
-                                },
-                            )
-                            .map(|entry| entry.value_sz)
-                            .unwrap_or(0);
+    /// Get the value of a key of a column family. If the key does not exist, return None.
+    pub fn get_cf(&mut self, family: &str, key: String) -> Result<Option<String>> {
+        let id = self.family(family)?.id;
+        self.read_value(family_key(Some(id), &key))
+    }
+
+    /// Set the value of a key of a column family, with the options of the family.
+    /// Returns the sequence number of the write.
+    pub fn set_cf(&mut self, family: &str, key: String, value: String) -> Result<u64> {
+        let mut batch = WriteBatch::new();
+        batch.set_cf(family, key, value);
+        self.write_batch(batch)
+    }
+
+    /// Remove a key of a column family.
+    /// Return an error if the key does not exist, otherwise the sequence number of the removal.
+    pub fn remove_cf(&mut self, family: &str, key: String) -> Result<u64> {
+        let id = self.family(family)?.id;
+        if self.index.get(&family_key(Some(id), &key))?.is_none() {
+            return Err(KvErr::KeyNotFound);
+        }
+        let mut batch = WriteBatch::new();
+        batch.remove_cf(family, key);
+        self.write_batch(batch)
+    }
+
+    /// Apply the writes of a batch atomically, they may span column families.
+    /// Removing a key that does not exist is skipped.
+    /// Nothing is written if a column family of the batch does not exist.
+    /// Returns the sequence number of the last write of the batch, or `last_sequence` if
+    /// the batch wrote nothing.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        self.check_writable()?;
+        let mut records = Vec::new();
+        // (index key, 记录在batch内的位置和长度, 过期时间和seq, 是否为tombstone)
+        let mut writes = Vec::with_capacity(batch.ops.len());
+        // batch内前面的写入决定后面的删除是否存在
+        let mut pending: HashMap<String, bool> = HashMap::new();
+        // 默认family的写入，写完之后通知watchers
+        let mut events = Vec::new();
+        let first_seq = self.last_seq + 1;
+        let mut seq = self.last_seq;
+        for op in batch.ops {
+            let start = records.len() as u64;
+            match op {
+                BatchOp::Set { family, key, value } => {
+                    seq += 1;
+                    let (meta, codec) = match &family {
+                        Some(family) => {
+                            let config = self.family(family)?;
+                            let meta = RecordMeta {
+                                cf: Some(config.id),
+                                expires: config.ttl_millis.map(|ttl| now_millis() + ttl),
+                                seq: Some(seq),
+                            };
+                            let codec = self.codec.with_compression(config.compression);
+                            (meta, Cow::Owned(codec))
+                        }
+                        None => {
+                            check_key(&key)?;
+                            let meta = RecordMeta {
+                                seq: Some(seq),
+                                ..RecordMeta::default()
+                            };
+                            (meta, Cow::Borrowed(&self.codec))
+                        }
+                    };
+                    Record::encode_put(&key, value.as_bytes(), meta, &codec, &mut records)?;
+                    let index_key = family_key(meta.cf, &key);
+                    if family.is_none() {
+                        events.push(WatchEvent::Set { seq, key, value });
+                    }
+                    pending.insert(index_key.clone(), true);
+                    writes.push((index_key, start, meta, false));
+                }
+                BatchOp::Remove { family, key } => {
+                    let cf = match &family {
+                        Some(family) => Some(self.family(family)?.id),
+                        None => {
+                            check_key(&key)?;
+                            None
+                        }
+                    };
+                    let index_key = family_key(cf, &key);
+                    let exists = match pending.get(&index_key) {
+                        Some(exists) => *exists,
+                        None => self.index.get(&index_key)?.is_some(),
+                    };
+                    if !exists {
+                        continue;
                     }
-                    Commands::Rm { key } => {
-                        redundant_data_sz +=
-                            store.remove(&key).map(|entry| entry.value_sz).unwrap_or(0);
-                        redundant_data_sz += after_offset - before_offset;
+                    seq += 1;
+                    let meta = RecordMeta {
+                        cf,
+                        expires: None,
+                        seq: Some(seq),
+                    };
+                    Record::encode_rm(&key, meta, &self.codec, &mut records)?;
+                    if family.is_none() {
+                        events.push(WatchEvent::Rm { seq, key });
                     }
-                    _ => continue,
+                    pending.insert(index_key.clone(), false);
+                    writes.push((index_key, start, meta, true));
                 }
-                current_file_offset = after_offset;
-                // 需要更新before offset，这是value pos的值
-                before_offset = after_offset;
             }
         }
-        Ok((
-            *data_files.last().unwrap_or(&0),
-            current_file_offset,
-            redundant_data_sz,
-        ))
-    }
-
-    /// 当冗余的数据超过一定的量之后，需要进行压缩
-    /// 压缩流程：
-    /// 1.
-    fn compact(&mut self) -> Result<()> {
-        self.create_new_file()?;
-        let new_file_name = self
+        if writes.is_empty() {
+            return Ok(self.last_seq);
+        }
+        for _ in first_seq..=seq {
+            self.next_seq();
+        }
+        let mut series_data = Vec::new();
+        Record::encode_batch(&records, &mut series_data)?;
+        let header_len = (series_data.len() - records.len()) as u64;
+        let file_path = self
             .dir_path
             .join(format!("store_file_{}.txt", self.current_file_id));
-        let mut before_offset = 0;
-        let mut buf_writer = BufWriter::new(OpenOptions::new().append(true).open(&new_file_name)?);
-        for entry in self.store.values_mut() {
-            let file_path = self
-                .dir_path
-                .join(format!("store_file_{}.txt", entry.file_id));
-            let mut reader = BufReader::new(File::open(&file_path)?);
-            reader.seek(SeekFrom::Start(entry.value_pos))?;
-            let mut data_reader = reader.take(entry.value_sz);
-            let len = io::copy(&mut data_reader, &mut buf_writer)?;
-            assert_ne!(len, 0);
-            *entry = KvEntry {
+        let mut file = OpenOptions::new().append(true).open(&file_path)?;
+        file.write_all(&series_data)?;
+        let records_pos = self.current_file_offset + header_len;
+        self.current_file_offset += series_data.len() as u64;
+        let ends = writes
+            .iter()
+            .skip(1)
+            .map(|(_, start, _, _)| *start)
+            .chain([records.len() as u64])
+            .collect::<Vec<_>>();
+        for ((index_key, start, meta, is_tombstone), end) in writes.into_iter().zip(ends) {
+            let entry = KvEntry {
                 file_id: self.current_file_id,
-                value_sz: len,
+                value_pos: records_pos + start,
+                value_sz: end - start,
+                expires: meta.expires,
+                seq: meta.seq,
+                chain: None,
+            };
+            self.cache.invalidate(&index_key);
+            if is_tombstone {
+                self.index.remove(index_key, entry)?;
+            } else {
+                self.index.insert(index_key, entry)?;
+            }
+        }
+        for event in events {
+            let key = event.key().to_owned();
+            self.watchers.notify(&key, || event);
+        }
+        self.after_write()?;
+        Ok(seq)
+    }
+
+    /// Watch the keys of the default column family starting with `prefix`.
+    /// Every later write of a matching key through this handle is sent to the returned
+    /// channel in order, a write by `set_reader` is sent without its value.
+    /// The watch ends when the receiver is dropped.
+    pub fn watch(&mut self, prefix: &str) -> Receiver<WatchEvent> {
+        self.watchers.add(prefix, Vec::new())
+    }
+
+    /// Like `watch`, but first sends the writes of matching keys with a sequence number
+    /// greater than `seq` that are still in the data files. Compaction keeps only the latest
+    /// record of each key, so earlier writes of a key may be missing, but the latest write
+    /// of every key written after `seq` is sent.
+    /// Returns `KvErr::Compacted` if compaction has dropped a removal written after `seq`,
+    /// the watcher has to rebuild its state instead of resuming.
+    pub fn watch_from(&mut self, prefix: &str, seq: u64) -> Result<Receiver<WatchEvent>> {
+        if seq < self.manifest.compacted_sequence {
+            return Err(KvErr::Compacted(self.manifest.compacted_sequence));
+        }
+        let mut backlog = Vec::new();
+        for &file_id in &self.manifest.files {
+            backlog.extend(
+                self.read_events(file_id, 0, u64::MAX, seq, |key| key.starts_with(prefix))?,
+            );
+        }
+        // 压缩后的文件中记录不再按seq排列
+        backlog.sort_by_key(WatchEvent::seq);
+        Ok(self.watchers.add(prefix, backlog))
+    }
+
+    /// 读取data file中[offset, end)之间seq大于`after`、key满足`wanted`的默认family的写入，
+    /// 转换成watch事件。只读模式下文件末尾可能有不完整的记录，读到那里为止
+    fn read_events(
+        &self,
+        file_id: u64,
+        offset: u64,
+        end: u64,
+        after: u64,
+        wanted: impl Fn(&str) -> bool,
+    ) -> Result<Vec<WatchEvent>> {
+        let file_path = self.dir_path.join(format!("store_file_{}.txt", file_id));
+        let mut reader = BufReader::new(File::open(&file_path)?);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut events = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let (record, header_len) = match Record::read_header(&mut reader) {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err.into()),
+            };
+            pos += header_len;
+            if let Record::Batch { .. } = record {
+                // batch中的记录紧跟在后面
+                continue;
+            }
+            pos += record.payload_len();
+            let meta = record.meta();
+            let wanted = meta.cf.is_none()
+                && meta.seq.is_some_and(|record_seq| record_seq > after)
+                && match &record {
+                    Record::Set { key, .. }
+                    | Record::Put { key, .. }
+                    | Record::Append { key, .. }
+                    | Record::Merge { key, .. }
+                    | Record::Rm { key, .. } => wanted(key),
+                    Record::Batch { .. } => false,
+                };
+            if !wanted {
+                reader.seek_relative(record.payload_len() as i64)?;
+                continue;
+            }
+            let record_seq = meta.seq.unwrap_or_default();
+            if record.is_stream() {
+                // 和实时的通知一样不发送set_reader写入的value，也不需要读出来
+                reader.seek_relative(record.payload_len() as i64)?;
+                events.push(WatchEvent::SetStream {
+                    seq: record_seq,
+                    key: record.into_key(),
+                });
+                continue;
+            }
+            let delta = record.delta().map(|(delta, _)| delta);
+            let (key, value) = record.read_bytes(&mut reader, &self.codec)?;
+            let value = value.map(String::from_utf8);
+            events.push(match value {
+                Some(Ok(suffix)) if delta == Some(Delta::Append) => WatchEvent::Append {
+                    seq: record_seq,
+                    key,
+                    suffix,
+                },
+                Some(Ok(operand)) if delta == Some(Delta::Merge) => WatchEvent::Merge {
+                    seq: record_seq,
+                    key,
+                    operand,
+                },
+                Some(Ok(value)) => WatchEvent::Set {
+                    seq: record_seq,
+                    key,
+                    value,
+                },
+                // 版本6之前set_reader写入的value没有标记，不是UTF-8的只可能是这种value
+                Some(Err(_)) if delta.is_none() => WatchEvent::SetStream {
+                    seq: record_seq,
+                    key,
+                },
+                Some(Err(err)) => return Err(KvErr::Corrupted(err.to_string())),
+                None => WatchEvent::Rm {
+                    seq: record_seq,
+                    key,
+                },
+            });
+        }
+        Ok(events)
+    }
+
+    /// Sequence number of the last write, 0 if nothing has been written.
+    /// Sequence numbers increase with every write and survive reopening and compaction.
+    pub fn last_sequence(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// 从delta链的最后一条记录沿着prev往前读，直到链开头的Put或者不存在的value，
+    /// 再按写入的顺序把operand合并到value中
+    fn read_chain(
+        dir_path: &Path,
+        codec: &Codec,
+        operator: Option<&dyn MergeOperator>,
+        key: &str,
+        entry: &KvEntry,
+    ) -> Result<String> {
+        let file_path = dir_path.join(format!("store_file_{}.txt", entry.file_id));
+        let mut reader = BufReader::new(File::open(&file_path)?);
+        let mut deltas = Vec::new();
+        let mut value = None;
+        let mut pos = Some(entry.value_pos);
+        while let Some(record_pos) = pos.take() {
+            reader.seek(SeekFrom::Start(record_pos))?;
+            let (record, _) = Record::read_header(&mut reader)?
+                .ok_or_else(|| KvErr::Corrupted("delta chain ends early".to_owned()))?;
+            match record.delta() {
+                Some((delta, prev)) => {
+                    pos = prev;
+                    let (_, operand) = record.read_value(&mut reader, codec)?;
+                    deltas.push((delta, operand.unwrap_or_default()));
+                }
+                None if matches!(record, Record::Set { .. } | Record::Put { .. }) => {
+                    value = record.read_value(&mut reader, codec)?.1;
+                }
+                None => return Err(KvErr::Corrupted("invalid record in delta chain".to_owned())),
+            }
+        }
+        for (delta, operand) in deltas.into_iter().rev() {
+            value = Some(apply_delta(operator, delta, key, value, &operand)?);
+        }
+        Ok(value.unwrap_or_default())
+    }
+
+    /// 分配下一次写入的sequence number
+    fn next_seq(&mut self) -> u64 {
+        self.last_seq += 1;
+        // 下次保存manifest时记录，压缩可能删掉带有最大seq的记录
+        self.manifest.last_sequence = self.last_seq;
+        self.last_seq
+    }
+
+    fn family(&self, name: &str) -> Result<&FamilyConfig> {
+        self.manifest
+            .families
+            .get(name)
+            .ok_or_else(|| KvErr::UnknownFamily(name.to_owned()))
+    }
+
+    /// 恢复流程：
+    /// 1. 按manifest中的顺序读取data files，磁盘索引从checkpoint的位置开始
+    /// 2. 对每个文件进行恢复，KvEntry
+    /// 3. 最后一个文件的offset作为active file的offset
+    fn recover(&mut self) -> Result<()> {
+        self.last_seq = self.manifest.last_sequence;
+        for data in self.manifest.files.clone() {
+            let offset = match self.replay_offset(data) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            // active file末尾没写完的记录（包括没写完的batch）是崩溃时还没有返回的写入，截掉它们
+            let is_active = self.manifest.files.last() == Some(&data);
+            self.current_file_offset = self.replay(data, offset, is_active)?;
+            if is_active {
+                let file_path = self.dir_path.join(format!("store_file_{}.txt", data));
+                let file = OpenOptions::new().write(true).open(&file_path)?;
+                if file.metadata()?.len() > self.current_file_offset {
+                    file.set_len(self.current_file_offset)?;
+                }
+            }
+            self.current_file_id = data;
+            self.checkpoint_index()?;
+        }
+        Ok(())
+    }
+
+    /// 回放一个data file的起始位置，磁盘索引的checkpoint已经包含的文件返回None
+    fn replay_offset(&self, file_id: u64) -> Option<u64> {
+        match &self.manifest.index {
+            Some(cp) if self.index.disk_id() == Some(cp.id) => match file_id.cmp(&cp.file_id) {
+                Ordering::Less => None,
+                Ordering::Equal => Some(cp.offset),
+                Ordering::Greater => Some(0),
+            },
+            _ => Some(0),
+        }
+    }
+
+    /// 磁盘索引内存中的写入太多时，把它们和旧的索引文件合并成新的索引文件，
+    /// manifest保存之后新的checkpoint才生效
+    fn checkpoint_index(&mut self) -> Result<()> {
+        if self.options.read_only || self.index.delta_len() < INDEX_DELTA_LIMIT {
+            return Ok(());
+        }
+        let index_id = self.next_index_id();
+        let relocation = self.index.relocate(index_id, |_, slot| Ok(Some(slot)))?;
+        self.manifest.index = Some(self.index.checkpoint(
+            index_id,
+            self.current_file_id,
+            self.current_file_offset,
+        ));
+        self.manifest.save(&self.dir_path)?;
+        self.index.apply(relocation)
+    }
+
+    fn next_index_id(&self) -> u64 {
+        self.manifest.index.as_ref().map_or(0, |cp| cp.id + 1)
+    }
+
+    /// 从offset开始回放一个data file，返回回放结束的offset。
+    /// 只读模式下writer可能正在追加记录，文件末尾不完整的记录留到下次refresh再读。
+    /// batch完整时才回放其中的记录，不完整的batch和不完整的记录一样处理。
+    /// 加密的store中没有认证tag的tombstone会让回放失败
+    fn replay(&mut self, file_id: u64, offset: u64, allow_partial: bool) -> Result<u64> {
+        let (dir_path, manifest, codec) = (&self.dir_path, &self.manifest, &self.codec);
+        let (index, last_seq) = (&mut self.index, &mut self.last_seq);
+        let file_path = dir_path.join(format!("store_file_{}.txt", file_id));
+        let file = File::open(&file_path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(offset))?;
+        index.file_stats.entry(file_id).or_default();
+        let mut before_offset = offset;
+        loop {
+            let (record, header_len) = match Record::read_header(&mut reader) {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(err) if allow_partial && err.is_eof() => break,
+                Err(err) => return Err(err.into()),
+            };
+            let payload_len = record.payload_len();
+            let after_offset = before_offset + header_len + payload_len;
+            if after_offset > file_len {
+                if allow_partial {
+                    break;
+                }
+                return Err(KvErr::Corrupted(format!(
+                    "truncated record in data file {}",
+                    file_id
+                )));
+            }
+            if let Record::Batch { .. } = record {
+                // 接着回放batch中的记录
+                before_offset += header_len;
+                continue;
+            }
+            match record {
+                // tombstone没有value可以在读的时候验证，回放时就检查它的tag
+                Record::Rm { .. } => record.check_tombstone(&mut reader, codec)?,
+                _ => reader.seek_relative(payload_len as i64)?,
+            }
+            let meta = record.meta();
+            *last_seq = (*last_seq).max(meta.seq.unwrap_or(0));
+            let entry = KvEntry {
+                file_id,
+                value_sz: after_offset - before_offset,
                 value_pos: before_offset,
+                expires: meta.expires,
+                seq: meta.seq,
+                chain: None,
             };
-            before_offset += len;
+            match record {
+                _ if meta.cf.is_some_and(|id| !manifest.has_family(id)) => {
+                    // 已经删除的column family的记录都是垃圾
+                    let usage = index.file_stats.entry(file_id).or_default();
+                    usage.total += entry.value_sz;
+                    usage.dead += entry.value_sz;
+                }
+                Record::Set { key, .. } | Record::Put { key, .. } => {
+                    index.insert(family_key(meta.cf, &key), entry)?
+                }
+                Record::Rm { key, .. } => index.remove(family_key(meta.cf, &key), entry)?,
+                Record::Append { key, prev, .. } | Record::Merge { key, prev, .. } => {
+                    let entry = KvEntry {
+                        chain: Some(Chain::default()),
+                        ..entry
+                    };
+                    match (prev, index.get(&key)?) {
+                        (None, _) => index.insert(key, entry)?,
+                        (Some(prev), Some(head))
+                            if head.file_id == file_id && head.value_pos == prev =>
+                        {
+                            index.extend(key, head.chained(entry))
+                        }
+                        _ => {
+                            return Err(KvErr::Corrupted(format!(
+                                "delta record of key {} without its previous record",
+                                key
+                            )))
+                        }
+                    }
+                }
+                Record::Batch { .. } => unreachable!("batch records are replayed above"),
+            }
+            // 需要更新before offset，这是value pos的值
+            before_offset = after_offset;
         }
-        buf_writer.flush()?;
+        Ok(before_offset)
+    }
 
-        let data_files = Self::find_dir_data_files(&self.dir_path)?;
-        for data in data_files.into_iter().filter(|x| *x < self.current_file_id) {
-            remove_file(self.dir_path.join(format!("store_file_{}.txt", data)))?;
+    /// 写入之后，active file太大时切换到新文件，垃圾太多时触发压缩
+    fn after_write(&mut self) -> Result<()> {
+        if self.current_file_offset > MAX_FILE_SIZE {
+            self.create_new_file()?;
+        }
+        self.checkpoint_index()?;
+        self.compact()
+    }
+
+    /// 当冗余的数据超过一定的量之后，需要进行压缩。
+    /// 只合并垃圾比例超过GARBAGE_RATIO的文件，压缩的开销和垃圾的多少成正比，
+    /// 而不是和整个数据集的大小成正比
+    fn compact(&mut self) -> Result<()> {
+        let now = now_millis();
+        if self.compaction_paused || self.index.dead_bytes(now) <= REDUNDAN_DATA_LIMIT {
+            return Ok(());
+        }
+        let mut inputs: Vec<u64> = self
+            .index
+            .file_stats
+            .iter()
+            .filter(|(data, stats)| {
+                stats.reclaimable(now) as f64 > stats.total as f64 * GARBAGE_RATIO
+                    || (stats.total == 0 && **data != self.current_file_id)
+            })
+            .map(|(data, _)| *data)
+            .collect();
+        if inputs.is_empty() {
+            return Ok(());
         }
+        // 太小的不可变文件顺便合并掉，否则它们只会越积越多
+        let small: Vec<u64> = self
+            .index
+            .file_stats
+            .iter()
+            .filter(|(data, stats)| {
+                **data != self.current_file_id
+                    && stats.total < MAX_FILE_SIZE / 4
+                    && !inputs.contains(data)
+            })
+            .map(|(data, _)| *data)
+            .collect();
+        inputs.extend(small);
+        inputs.sort_unstable();
+        self.merge_files(inputs)
+    }
 
-        self.create_new_file()?;
+    /// 把inputs中的data files合并成新的文件，每个输出文件不超过MAX_FILE_SIZE
+    /// 合并流程：
+    /// 1. 把inputs中有效的记录写到输出中，并fsync。active file是input时输出是临时文件
+    ///    store_file_N.tmp，否则直接追加到active file的末尾，写满后再切换到临时文件
+    /// 2. 临时文件rename为store_file_N.txt，fsync目录
+    /// 3. 最后一个输出文件作为新的active file，把manifest更新为合并后的文件列表，
+    ///    这一步是提交点
+    /// 4. 删除inputs
+    ///
+    /// 在提交点之前崩溃，manifest仍然指向旧文件，临时文件和合并文件会在open时被删除，
+    /// 追加到active file中的记录是inputs中最新记录的副本，恢复时重放它们不会改变结果；
+    /// 在提交点之后崩溃，没删完的旧文件不在manifest中，同样会在open时被删除
+    ///
+    /// active file的id比所有不可变文件都大，合并输出在它的末尾或者更大的id中，
+    /// 按id顺序恢复时输出中的记录会覆盖剩下文件中这些key的旧记录。
+    /// tombstone只有在比它旧的文件全部被合并掉以后才能丢弃，
+    /// 否则旧文件中被删除的key会在恢复时重新出现
+    fn merge_files(&mut self, inputs: Vec<u64>) -> Result<()> {
+        let start = Instant::now();
+        let remaining: Vec<u64> = self
+            .manifest
+            .files
+            .iter()
+            .copied()
+            .filter(|data| !inputs.contains(data))
+            .collect();
+        // active file不是input时，输出追加到它的末尾，不需要切换新的active file
+        let appended = if inputs.contains(&self.current_file_id) {
+            None
+        } else {
+            Some((self.current_file_id, self.current_file_offset))
+        };
+        let mut output = MergeOutput::new(&self.dir_path, appended, self.manifest.next_file_id)?;
+        let dir_path = &self.dir_path;
+        let codec = &self.codec;
+        let operator = self.options.merge_operator.as_deref();
+        let mut expired = Vec::new();
+        // 复制到输出中的有过期时间的value，输出文件要继续跟踪它们
+        let mut expiring = Vec::new();
+        // 丢弃的tombstone中最大的seq，从它之前恢复的watcher收不到这些删除
+        let mut compacted_sequence = self.manifest.compacted_sequence;
+        let relocation = self
+            .index
+            .relocate(self.next_index_id(), |key, slot| match slot {
+                Slot::Live(entry) if inputs.contains(&entry.file_id) => {
+                    // 过期的value和删除一样，有更旧的文件时换成一个tombstone，
+                    // 挡住旧文件中这个key的记录，没有更旧的文件时直接丢弃
+                    if entry.is_expired() {
+                        expired.push(slot);
+                        if remaining.iter().any(|data| *data < entry.file_id) {
+                            Ok(Some(Slot::Dead(output.write_rm(key, entry.seq, codec)?)))
+                        } else {
+                            Ok(None)
+                        }
+                    } else if entry.chain.is_some() {
+                        // delta链只在一个文件中，整条链合并成一条Put
+                        let value = Self::read_chain(dir_path, codec, operator, key, &entry)?;
+                        Ok(Some(Slot::Live(
+                            output.write_put(key, &value, entry.seq, codec)?,
+                        )))
+                    } else {
+                        let moved = output.copy_record(dir_path, &entry)?;
+                        if moved.expires.is_some() {
+                            expiring.push(moved);
+                        }
+                        Ok(Some(Slot::Live(moved)))
+                    }
+                }
+                Slot::Dead(tombstone) if inputs.contains(&tombstone.file_id) => {
+                    if remaining.iter().any(|data| *data < tombstone.file_id) {
+                        Ok(Some(Slot::Dead(output.copy_record(dir_path, &tombstone)?)))
+                    } else {
+                        compacted_sequence = compacted_sequence.max(tombstone.seq.unwrap_or(0));
+                        Ok(None)
+                    }
+                }
+                slot => Ok(Some(slot)),
+            })?;
+        let outputs = output.finish()?;
+        self.index.discard(&expired);
+
+        let input_bytes: u64 = inputs
+            .iter()
+            .filter_map(|data| self.index.file_stats.get(data))
+            .map(|usage| usage.total)
+            .sum();
+        let output_bytes: u64 = outputs.iter().map(|(_, total)| total).sum::<u64>()
+            - appended.map_or(0, |(_, offset)| offset);
+        let reclaimed = input_bytes.saturating_sub(output_bytes);
+        let compaction = &mut self.manifest.compaction;
+        compaction.compactions += 1;
+        compaction.last_duration_micros = start.elapsed().as_micros() as u64;
+        compaction.last_reclaimed_bytes = reclaimed;
+        compaction.total_reclaimed_bytes += reclaimed;
+        self.manifest.compacted_sequence = compacted_sequence;
+        self.manifest.files = remaining;
+        for data in &inputs {
+            self.index.file_stats.remove(data);
+        }
+        for &(data, total) in &outputs {
+            if !self.manifest.files.contains(&data) {
+                self.manifest.files.push(data);
+            }
+            self.manifest.next_file_id = self.manifest.next_file_id.max(data + 1);
+            self.index.file_stats.entry(data).or_default().total = total;
+        }
+        for entry in &expiring {
+            if let Some(usage) = self.index.file_stats.get_mut(&entry.file_id) {
+                usage.track(entry);
+            }
+        }
+        // 新的索引文件覆盖新的active file中当前位置之前的所有记录，和合并结果一起提交
+        match outputs.last() {
+            Some(&(data, total)) => {
+                self.current_file_id = data;
+                self.current_file_offset = total;
+                if let Relocation::Disk(index_id) = relocation {
+                    self.manifest.index = Some(self.index.checkpoint(index_id, data, total));
+                }
+                self.manifest.save(&self.dir_path)?;
+            }
+            None => {
+                if let Relocation::Disk(index_id) = relocation {
+                    self.manifest.index = Some(self.index.checkpoint(
+                        index_id,
+                        self.manifest.next_file_id,
+                        0,
+                    ));
+                }
+                self.create_new_file()?;
+            }
+        }
+        self.index.apply(relocation)?;
+        for data in inputs {
+            self.mmaps.remove(&data);
+            remove_file(self.dir_path.join(format!("store_file_{}.txt", data)))?;
+        }
         Ok(())
     }
 
+    /// 对目录下的LOCK文件加排他锁（flock），防止两个进程同时写同一个store。
+    /// 进程退出或者File被drop时锁会自动释放
+    pub(crate) fn lock_dir(dir_path: &Path) -> Result<File> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir_path.join(LOCK_FILE))?;
+        match lock.try_lock() {
+            Ok(()) => Ok(lock),
+            Err(TryLockError::WouldBlock) => Err(KvErr::Locked),
+            Err(TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+
+    /// 返回不可变data file的mmap，第一次访问时才映射。
+    /// active file还在追加写入，仍然用普通的读
+    fn mmap(&mut self, file_id: u64) -> Result<Option<&Mmap>> {
+        if !self.options.mmap_reads || file_id == self.current_file_id {
+            return Ok(None);
+        }
+        if !self.mmaps.contains_key(&file_id) {
+            let file_path = self.dir_path.join(format!("store_file_{}.txt", file_id));
+            let file = File::open(&file_path)?;
+            // SAFETY: 除了active file，data file写完之后不会再被修改，
+            // 压缩只会删除文件，已经映射的内存在unmap之前仍然有效
+            let mmap = unsafe { Mmap::map(&file)? };
+            self.mmaps.insert(file_id, mmap);
+        }
+        Ok(self.mmaps.get(&file_id))
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            Err(KvErr::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 分配新的file id作为active file，并记录到manifest中
     fn create_new_file(&mut self) -> Result<()> {
-        self.current_file_id += 1;
+        self.current_file_id = self.manifest.next_file_id;
+        self.manifest.next_file_id += 1;
         let new_file_name = self
             .dir_path
             .join(format!("store_file_{}.txt", self.current_file_id));
         let _ = OpenOptions::new()
             .create(true)
-            .append(true)
+            .truncate(true)
+            .write(true)
             .open(&new_file_name)?;
-        //
+        self.manifest.files.push(self.current_file_id);
+        self.manifest.save(&self.dir_path)?;
+        self.index.file_stats.entry(self.current_file_id).or_default();
         self.current_file_offset = 0;
         Ok(())
     }
 
-    fn find_dir_data_files(dir_path: &PathBuf) -> Result<Vec<u64>> {
+    /// manifest中的data files是否有记录
+    fn has_data(dir_path: &Path, manifest: &Manifest) -> Result<bool> {
+        for &file_id in &manifest.files {
+            match std::fs::metadata(dir_path.join(format!("store_file_{}.txt", file_id))) {
+                Ok(metadata) if metadata.len() > 0 => return Ok(true),
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+
+    /// 删除目录中不在manifest里的data files、索引文件和压缩留下的临时文件，
+    /// 它们是崩溃前没来得及提交或者没来得及删除的文件
+    fn remove_stale_files(dir_path: &Path, manifest: &Manifest) -> Result<()> {
+        for data in Self::find_dir_data_files(dir_path)? {
+            if !manifest.files.contains(&data) {
+                remove_file(dir_path.join(format!("store_file_{}.txt", data)))?;
+            }
+        }
+        let index_file = manifest
+            .index
+            .as_ref()
+            .map(|cp| index_path(dir_path, cp.id));
+        for path in read_dir(dir_path)?.flat_map(|res| res.map(|e| e.path())) {
+            let filename = path
+                .file_name()
+                .and_then(|filename| filename.to_str())
+                .unwrap_or_default();
+            let is_tmp = path.extension() == Some("tmp".as_ref())
+                && (filename.starts_with("store_file_") || filename.starts_with("index_"));
+            let is_stale_index = (path.extension() == Some("idx".as_ref())
+                || path.extension() == Some("bloom".as_ref()))
+                && filename.starts_with("index_")
+                && index_file != Some(path.with_extension("idx"));
+            if is_tmp || is_stale_index {
+                remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn find_dir_data_files(dir_path: &Path) -> Result<Vec<u64>> {
         let mut data_files: Vec<u64> = read_dir(dir_path)?
             .flat_map(|res| res.map(|e| e.path()))
             .filter(|path| path.is_file() && path.extension() == Some("txt".as_ref()))
@@ -257,3 +2001,229 @@ impl KvStore {
         Ok(data_files)
     }
 }
+
+/// 合并的输出，当前文件写满MAX_FILE_SIZE后切换到下一个临时文件
+struct MergeOutput<'a> {
+    dir_path: &'a Path,
+    /// finished output files and their sizes
+    files: Vec<(u64, u64)>,
+    file_id: u64,
+    writer: BufWriter<File>,
+    offset: u64,
+    /// the active file the output starts in, it is appended to instead of renamed
+    active: Option<u64>,
+    next_file_id: u64,
+}
+
+impl<'a> MergeOutput<'a> {
+    /// active为Some((file_id, offset))时从active file的offset处开始追加，
+    /// 否则从next_file_id开始写临时文件
+    fn new(dir_path: &'a Path, active: Option<(u64, u64)>, next_file_id: u64) -> Result<Self> {
+        let (file_id, writer, offset, next_file_id) = match active {
+            Some((file_id, offset)) => {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .open(dir_path.join(format!("store_file_{}.txt", file_id)))?;
+                (file_id, BufWriter::new(file), offset, next_file_id)
+            }
+            None => (
+                next_file_id,
+                Self::create(dir_path, next_file_id)?,
+                0,
+                next_file_id + 1,
+            ),
+        };
+        Ok(MergeOutput {
+            dir_path,
+            files: Vec::new(),
+            file_id,
+            writer,
+            offset,
+            active: active.map(|(file_id, _)| file_id),
+            next_file_id,
+        })
+    }
+
+    fn create(dir_path: &Path, file_id: u64) -> Result<BufWriter<File>> {
+        let tmp_path = dir_path.join(format!("store_file_{}.tmp", file_id));
+        Ok(BufWriter::new(File::create(tmp_path)?))
+    }
+
+    /// 把一条记录原样复制到输出文件中，返回它在输出文件中的位置
+    fn copy_record(&mut self, dir_path: &Path, entry: &KvEntry) -> Result<KvEntry> {
+        if self.offset >= MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        let file_path = dir_path.join(format!("store_file_{}.txt", entry.file_id));
+        let mut reader = BufReader::new(File::open(&file_path)?);
+        reader.seek(SeekFrom::Start(entry.value_pos))?;
+        let mut data_reader = reader.take(entry.value_sz);
+        let len = io::copy(&mut data_reader, &mut self.writer)?;
+        assert_ne!(len, 0);
+        self.offset += len;
+        Ok(KvEntry {
+            file_id: self.file_id,
+            value_sz: len,
+            value_pos: self.offset - len,
+            expires: entry.expires,
+            seq: entry.seq,
+            chain: None,
+        })
+    }
+
+    /// 把合并好的value写成一条新的Put记录
+    fn write_put(
+        &mut self,
+        key: &str,
+        value: &str,
+        seq: Option<u64>,
+        codec: &Codec,
+    ) -> Result<KvEntry> {
+        if self.offset >= MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        let meta = RecordMeta {
+            seq,
+            ..RecordMeta::default()
+        };
+        let mut record = Vec::new();
+        Record::encode_put(key, value.as_bytes(), meta, codec, &mut record)?;
+        self.writer.write_all(&record)?;
+        let len = record.len() as u64;
+        self.offset += len;
+        Ok(KvEntry {
+            file_id: self.file_id,
+            value_sz: len,
+            value_pos: self.offset - len,
+            expires: None,
+            seq,
+            chain: None,
+        })
+    }
+
+    /// 把过期的value写成一条tombstone，`key`是index中的key
+    fn write_rm(&mut self, key: &str, seq: Option<u64>, codec: &Codec) -> Result<KvEntry> {
+        if self.offset >= MAX_FILE_SIZE {
+            self.rotate()?;
+        }
+        let (cf, key) = split_family_key(key);
+        let meta = RecordMeta {
+            cf,
+            seq,
+            ..RecordMeta::default()
+        };
+        let mut record = Vec::new();
+        Record::encode_rm(key, meta, codec, &mut record)?;
+        self.writer.write_all(&record)?;
+        let len = record.len() as u64;
+        self.offset += len;
+        Ok(KvEntry {
+            file_id: self.file_id,
+            value_sz: len,
+            value_pos: self.offset - len,
+            expires: None,
+            seq,
+            chain: None,
+        })
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let writer = std::mem::replace(
+            &mut self.writer,
+            Self::create(self.dir_path, self.next_file_id)?,
+        );
+        Self::sync(writer)?;
+        self.files.push((self.file_id, self.offset));
+        self.file_id = self.next_file_id;
+        self.next_file_id += 1;
+        self.offset = 0;
+        Ok(())
+    }
+
+    fn sync(writer: BufWriter<File>) -> Result<()> {
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// fsync所有输出，rename成data file，返回输出文件的id和大小。
+    /// 最后一个文件为空时直接删除，追加写入的active file总是返回
+    fn finish(mut self) -> Result<Vec<(u64, u64)>> {
+        Self::sync(self.writer)?;
+        let tmp_path = self
+            .dir_path
+            .join(format!("store_file_{}.tmp", self.file_id));
+        if self.offset > 0 || self.active == Some(self.file_id) {
+            self.files.push((self.file_id, self.offset));
+        } else {
+            remove_file(tmp_path)?;
+        }
+        for (data, _) in &self.files {
+            if self.active == Some(*data) {
+                continue;
+            }
+            rename(
+                self.dir_path.join(format!("store_file_{}.tmp", data)),
+                self.dir_path.join(format!("store_file_{}.txt", data)),
+            )?;
+        }
+        File::open(self.dir_path)?.sync_all()?;
+        Ok(self.files)
+    }
+}
+
+/// 把一个delta记录的operand合并到value中，value为None表示key不存在
+fn apply_delta(
+    operator: Option<&dyn MergeOperator>,
+    delta: Delta,
+    key: &str,
+    value: Option<String>,
+    operand: &str,
+) -> Result<String> {
+    match delta {
+        Delta::Append => Ok(value.unwrap_or_default() + operand),
+        Delta::Merge => {
+            let operator = operator.ok_or(KvErr::NoMergeOperator)?;
+            Ok(operator.merge(key, value.as_deref(), operand))
+        }
+    }
+}
+
+/// 默认family的key直接作为index中的key，其他family的key前面加上`\0id\0`
+fn family_key(cf: Option<u64>, key: &str) -> String {
+    match cf {
+        Some(id) => format!("\0{}\0{}", id, key),
+        None => key.to_owned(),
+    }
+}
+
+/// `family_key`的逆操作，返回family的id和family中的key
+fn split_family_key(index_key: &str) -> (Option<u64>, &str) {
+    let family = index_key
+        .strip_prefix('\0')
+        .and_then(|rest| rest.split_once('\0'))
+        .and_then(|(id, key)| Some((id.parse().ok()?, key)));
+    match family {
+        Some((id, key)) => (Some(id), key),
+        None => (None, index_key),
+    }
+}
+
+/// 过期时间所在的秒的结束时间，这一秒的value到这个时间全部过期
+fn expiry_second(expires: u64) -> u64 {
+    expires.div_ceil(1000) * 1000
+}
+
+/// `\0`开头的index key属于其他family，默认family的key不能以`\0`开头
+fn check_key(key: &str) -> Result<()> {
+    if key.starts_with('\0') {
+        return Err(KvErr::ReservedKey(key.to_owned()));
+    }
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
@@ -1,9 +1,39 @@
 /// KvStore crate
 #[deny(missing_docs)]
 mod kv;
+mod batch;
 mod error;
 mod command;
+mod manifest;
+mod stats;
+mod options;
+mod record;
+mod protocol;
+mod server;
+mod client;
+mod engine;
+mod lsm;
+mod mem;
+mod disk_index;
+mod sstable;
+mod bloom;
+mod lru;
+mod cache;
+mod watch;
+mod merge;
 pub use kv::KvStore;
+pub use batch::WriteBatch;
+pub use watch::WatchEvent;
+pub use merge::MergeOperator;
+pub use engine::KvsEngine;
+pub use lsm::LsmStore;
+pub use mem::MemStore;
 pub use error::Result;
 pub use error::KvErr;
 pub use command::Commands;
+pub use protocol::{Reply, Request, Response};
+pub use server::KvsServer;
+pub use client::KvsClient;
+pub use stats::{BloomStats, CacheStats, CompactionStats, FileStats, Stats};
+pub use options::{FamilyOptions, IndexMode, Options};
+pub use record::{Compression, EncryptionKey, ValueReader};
@@ -0,0 +1,90 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Least recently used cache, bounded by the total weight of its values
+pub(crate) struct LruCache<K, V> {
+    capacity: u64,
+    weight: u64,
+    /// incremented on every access, orders the entries by recency
+    tick: u64,
+    entries: HashMap<K, CacheEntry<V>>,
+    /// keys by last access tick, the first one is evicted first
+    order: BTreeMap<u64, K>,
+}
+
+struct CacheEntry<V> {
+    value: V,
+    weight: u64,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: u64) -> Self {
+        LruCache {
+            capacity,
+            weight: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    /// Get a value and mark it as most recently used
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        Some(&entry.value)
+    }
+
+    /// Insert a value, evicting the least recently used values over capacity.
+    /// A value heavier than the whole capacity is not cached.
+    pub fn insert(&mut self, key: K, value: V, weight: u64) {
+        self.remove(&key);
+        if weight > self.capacity {
+            return;
+        }
+        while self.weight + weight > self.capacity {
+            match self.order.pop_first() {
+                Some((_, oldest)) => {
+                    if let Some(entry) = self.entries.remove(&oldest) {
+                        self.weight -= entry.weight;
+                    }
+                }
+                None => break,
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.weight += weight;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                weight,
+                tick: self.tick,
+            },
+        );
+    }
+
+    /// Remove a value from the cache
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.weight -= entry.weight;
+        Some(entry.value)
+    }
+
+    /// total weight of the cached values
+    pub fn weight(&self) -> u64 {
+        self.weight
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.weight = 0;
+    }
+}
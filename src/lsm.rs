@@ -0,0 +1,464 @@
+use crate::manifest::Manifest;
+use crate::record::{Codec, Record, RecordMeta};
+use crate::sstable::{remove_table, search_page, Table, TableWriter};
+use crate::{BloomStats, Compression, KvErr, KvStore, Options, Result};
+use std::collections::BTreeMap;
+use std::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use std::vec;
+
+const ENGINE: &str = "lsm";
+/// the memtable is flushed to a level 0 table once its log reaches this size
+const MEMTABLE_LIMIT: u64 = 1024 * 1024;
+/// a level is merged into one table of the next level once it has this many tables
+const TIER_SIZE: usize = 4;
+
+/// LSM-tree store, keeps keys sorted on disk.
+/// An alternative to the log-structured `KvStore` for range scans.
+///
+/// 写入先追加到write-ahead log，再放入内存中的memtable，memtable满了之后写成level 0的SSTable。
+/// 压缩是分层（tiered）的：一层的SSTable达到TIER_SIZE个时合并成下一层的一个SSTable。
+/// 同一层中后写的table更新，每一层都比下一层新，
+/// 读取时按memtable、level 0从新到旧、level 1从新到旧……的顺序查找
+pub struct LsmStore {
+    dir_path: PathBuf,
+    /// `files` holds the write-ahead log, `levels` the SSTables
+    manifest: Manifest,
+    codec: Codec,
+    /// latest writes, `None` is a tombstone
+    memtable: BTreeMap<String, Option<String>>,
+    /// bytes of the write-ahead log
+    wal_size: u64,
+    wal: File,
+    /// SSTables of every level, oldest first, in the same order as `manifest.levels`
+    levels: Vec<Vec<Table>>,
+    /// false positive rate of the filters of new SSTables
+    fp_rate: f64,
+    bloom: BloomStats,
+    /// exclusive lock on the directory, released on drop
+    _lock: File,
+}
+
+impl LsmStore {
+    /// Open the LsmStore at a given path
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmStore> {
+        Self::open_with(path, Options::default())
+    }
+
+    /// Open the LsmStore at a given path with the given options.
+    /// Only `bloom_fp_rate` applies to this engine, values are neither compressed nor
+    /// encrypted: `compression` and `encryption_key` are rejected with `InvalidOptions`,
+    /// the other options are ignored.
+    pub fn open_with(path: impl Into<PathBuf>, options: Options) -> Result<LsmStore> {
+        let fp_rate = options.fp_rate()?;
+        if options.compression != Compression::None {
+            return Err(KvErr::InvalidOptions(
+                "LsmStore does not compress values".to_owned(),
+            ));
+        }
+        if options.encryption_key.is_some() {
+            return Err(KvErr::InvalidOptions(
+                "LsmStore does not encrypt values".to_owned(),
+            ));
+        }
+        let dir_path = path.into();
+        create_dir_all(&dir_path)?;
+        let lock = KvStore::lock_dir(&dir_path)?;
+        let mut manifest =
+            Manifest::load(&dir_path, ENGINE)?.unwrap_or_else(|| Manifest::new(ENGINE));
+        if manifest.files.is_empty() {
+            let wal_id = manifest.next_file_id;
+            manifest.next_file_id += 1;
+            File::create(wal_path(&dir_path, wal_id))?;
+            manifest.files.push(wal_id);
+            manifest.save(&dir_path)?;
+        }
+        Self::remove_stale_files(&dir_path, &manifest)?;
+        let levels = manifest
+            .levels
+            .iter()
+            .map(|level| {
+                level
+                    .iter()
+                    .map(|id| Table::open(&table_path(&dir_path, *id)))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let codec = Codec::new(&options);
+        let mut memtable = BTreeMap::new();
+        let mut wal_size = 0;
+        for wal_id in &manifest.files {
+            wal_size += Self::replay(&wal_path(&dir_path, *wal_id), &codec, &mut memtable)?;
+        }
+        let wal = OpenOptions::new()
+            .append(true)
+            .open(wal_path(&dir_path, *manifest.files.last().unwrap()))?;
+        Ok(LsmStore {
+            dir_path,
+            manifest,
+            codec,
+            memtable,
+            wal_size,
+            wal,
+            levels,
+            fp_rate,
+            bloom: BloomStats::default(),
+            _lock: lock,
+        })
+    }
+
+    /// Get the string value of a string key. If the key does not exist, return None.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.memtable.get(&key) {
+            return Ok(value.clone());
+        }
+        for table in self.levels.iter().flat_map(|level| level.iter().rev()) {
+            self.bloom.checks += 1;
+            if !table.may_contain(&key) {
+                self.bloom.skipped_reads += 1;
+                continue;
+            }
+            let page_no = match table.find_page(&key) {
+                Some(page_no) => page_no,
+                None => {
+                    self.bloom.false_positives += 1;
+                    continue;
+                }
+            };
+            let page = table.read_page::<Option<String>>(page_no)?;
+            match search_page(&page, &key) {
+                Some(value) => return Ok(value.clone()),
+                None => self.bloom.false_positives += 1,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return the effectiveness of the SSTable Bloom filters since the store was opened
+    pub fn bloom_stats(&self) -> BloomStats {
+        self.bloom
+    }
+
+    /// Set the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let mut buf = Vec::new();
+        Record::encode_put(
+            &key,
+            value.as_bytes(),
+            RecordMeta::default(),
+            &self.codec,
+            &mut buf,
+        )?;
+        self.append_wal(&buf)?;
+        self.memtable.insert(key, Some(value));
+        self.after_write()
+    }
+
+    /// Remove a given key.
+    /// Return an error if the key does not exist or is not removed successfully.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvErr::KeyNotFound);
+        }
+        let mut buf = Vec::new();
+        Record::encode_rm(&key, RecordMeta::default(), &self.codec, &mut buf)?;
+        self.append_wal(&buf)?;
+        self.memtable.insert(key, None);
+        self.after_write()
+    }
+
+    /// Return the live key/value pairs in a range of keys, in key order
+    pub fn scan(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let start = match range.start_bound() {
+            Bound::Included(start) | Bound::Excluded(start) => Some(start.as_str()),
+            Bound::Unbounded => None,
+        };
+        let memtable: Vec<_> = self
+            .memtable
+            .range::<String, _>((range.start_bound(), Bound::Unbounded))
+            .take_while(|(key, _)| range.contains(*key))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let mut sources = vec![TableIter::from_entries(memtable)];
+        for table in self.levels.iter().flat_map(|level| level.iter().rev()) {
+            sources.push(TableIter::new(table, start));
+        }
+        let mut merged = MergeIter::new(sources)?;
+        let mut pairs = Vec::new();
+        while let Some((key, value)) = merged.next()? {
+            if !range.contains(&key) {
+                match range.end_bound() {
+                    Bound::Included(end) if key > *end => break,
+                    Bound::Excluded(end) if key >= *end => break,
+                    _ => continue,
+                }
+            }
+            if let Some(value) = value {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// 回放write-ahead log，返回完整记录的长度。崩溃时没写完的记录被截掉，
+    /// 之后的写入追加在完整的记录后面
+    fn replay(
+        path: &Path,
+        codec: &Codec,
+        memtable: &mut BTreeMap<String, Option<String>>,
+    ) -> Result<u64> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+        loop {
+            let (record, header_len) = match Record::read_header(&mut reader) {
+                Ok(Some(next)) => next,
+                Ok(None) => break,
+                Err(err) if err.is_eof() => break,
+                Err(err) => return Err(err.into()),
+            };
+            let record_len = header_len + record.payload_len();
+            if offset + record_len > file_len {
+                break;
+            }
+            let (key, value) = record.read_value(&mut reader, codec)?;
+            memtable.insert(key, value);
+            offset += record_len;
+        }
+        if offset < file_len {
+            OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+        }
+        Ok(offset)
+    }
+
+    /// 写入返回之前记录已经落盘，崩溃之后可以从log回放
+    fn append_wal(&mut self, record: &[u8]) -> Result<()> {
+        self.wal.write_all(record)?;
+        self.wal.sync_data()?;
+        self.wal_size += record.len() as u64;
+        Ok(())
+    }
+
+    fn after_write(&mut self) -> Result<()> {
+        if self.wal_size > MEMTABLE_LIMIT {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// 把memtable写成level 0的SSTable，然后切换到新的write-ahead log。
+    /// manifest保存之前崩溃，旧的log仍然有效，没有提交的SSTable在open时被删除
+    fn flush(&mut self) -> Result<()> {
+        let table_id = self.manifest.next_file_id;
+        let wal_id = table_id + 1;
+        self.manifest.next_file_id += 2;
+        let mut writer = TableWriter::create(&table_path(&self.dir_path, table_id), self.fp_rate)?;
+        for (key, value) in &self.memtable {
+            writer.push(key, value)?;
+        }
+        writer.finish()?;
+        File::create(wal_path(&self.dir_path, wal_id))?;
+
+        let old_wals = std::mem::replace(&mut self.manifest.files, vec![wal_id]);
+        self.push_table(0, table_id)?;
+        self.manifest.save(&self.dir_path)?;
+        self.wal = OpenOptions::new()
+            .append(true)
+            .open(wal_path(&self.dir_path, wal_id))?;
+        self.wal_size = 0;
+        self.memtable.clear();
+        for wal_id in old_wals {
+            remove_file(wal_path(&self.dir_path, wal_id))?;
+        }
+        self.compact()
+    }
+
+    /// 逐层检查，一层的table达到TIER_SIZE个时合并到下一层
+    fn compact(&mut self) -> Result<()> {
+        let mut level = 0;
+        while level < self.levels.len() {
+            if self.levels[level].len() >= TIER_SIZE {
+                self.merge_level(level)?;
+            }
+            level += 1;
+        }
+        Ok(())
+    }
+
+    /// 把一层的所有table合并成下一层最新的一个table。
+    /// 下一层和更深的层都没有table时，tombstone没有需要遮住的旧记录，直接丢弃
+    fn merge_level(&mut self, level: usize) -> Result<()> {
+        let start = Instant::now();
+        let drop_tombstones = self.levels[level + 1..]
+            .iter()
+            .all(|tables| tables.is_empty());
+        let table_id = self.manifest.next_file_id;
+        self.manifest.next_file_id += 1;
+        let output_path = table_path(&self.dir_path, table_id);
+        let mut writer = TableWriter::create(&output_path, self.fp_rate)?;
+        let sources = self.levels[level]
+            .iter()
+            .rev()
+            .map(|table| TableIter::new(table, None))
+            .collect();
+        let mut merged = MergeIter::new(sources)?;
+        while let Some((key, value)) = merged.next()? {
+            if value.is_some() || !drop_tombstones {
+                writer.push(&key, &value)?;
+            }
+        }
+        writer.finish()?;
+
+        let inputs = std::mem::take(&mut self.manifest.levels[level]);
+        let input_bytes = inputs
+            .iter()
+            .map(|id| {
+                table_path(&self.dir_path, *id)
+                    .metadata()
+                    .map(|meta| meta.len())
+            })
+            .sum::<std::io::Result<u64>>()?;
+        let reclaimed = input_bytes.saturating_sub(output_path.metadata()?.len());
+        let compaction = &mut self.manifest.compaction;
+        compaction.compactions += 1;
+        compaction.last_duration_micros = start.elapsed().as_micros() as u64;
+        compaction.last_reclaimed_bytes = reclaimed;
+        compaction.total_reclaimed_bytes += reclaimed;
+        self.levels[level].clear();
+        self.push_table(level + 1, table_id)?;
+        self.manifest.save(&self.dir_path)?;
+        for id in inputs {
+            remove_table(&table_path(&self.dir_path, id))?;
+        }
+        Ok(())
+    }
+
+    /// 把table加到一层的最后，需要时创建这一层
+    fn push_table(&mut self, level: usize, table_id: u64) -> Result<()> {
+        while self.levels.len() <= level {
+            self.levels.push(Vec::new());
+            self.manifest.levels.push(Vec::new());
+        }
+        self.levels[level].push(Table::open(&table_path(&self.dir_path, table_id))?);
+        self.manifest.levels[level].push(table_id);
+        Ok(())
+    }
+
+    /// 删除不在manifest中的SSTable和它的Bloom filter、write-ahead log和没写完的临时文件
+    fn remove_stale_files(dir_path: &Path, manifest: &Manifest) -> Result<()> {
+        for path in read_dir(dir_path)?.flat_map(|res| res.map(|e| e.path())) {
+            let filename = path
+                .file_name()
+                .and_then(|filename| filename.to_str())
+                .unwrap_or_default();
+            let id = |prefix: &str, extension: &str| {
+                filename
+                    .strip_prefix(prefix)
+                    .and_then(|name| name.strip_suffix(extension))
+                    .and_then(|id| id.parse::<u64>().ok())
+            };
+            let table_id = id("sst_", ".sst").or_else(|| id("sst_", ".bloom"));
+            let is_stale = match (table_id, id("wal_", ".log")) {
+                (Some(id), _) => !manifest.levels.iter().any(|level| level.contains(&id)),
+                (_, Some(id)) => !manifest.files.contains(&id),
+                _ => id("sst_", ".tmp").is_some(),
+            };
+            if is_stale {
+                remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn table_path(dir_path: &Path, id: u64) -> PathBuf {
+    dir_path.join(format!("sst_{}.sst", id))
+}
+
+fn wal_path(dir_path: &Path, id: u64) -> PathBuf {
+    dir_path.join(format!("wal_{}.log", id))
+}
+
+type Entry = (String, Option<String>);
+
+/// 按顺序逐个page读取一个table
+struct TableIter<'a> {
+    table: Option<&'a Table>,
+    next_page: usize,
+    entries: vec::IntoIter<Entry>,
+}
+
+impl<'a> TableIter<'a> {
+    /// Iterate a table from the page that may contain `start`
+    fn new(table: &'a Table, start: Option<&str>) -> Self {
+        TableIter {
+            table: Some(table),
+            next_page: start.and_then(|start| table.find_page(start)).unwrap_or(0),
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    fn from_entries(entries: Vec<Entry>) -> Self {
+        TableIter {
+            table: None,
+            next_page: 0,
+            entries: entries.into_iter(),
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Entry>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Ok(Some(entry));
+            }
+            match self.table {
+                Some(table) if self.next_page < table.page_count() => {
+                    self.entries = table.read_page(self.next_page)?.into_iter();
+                    self.next_page += 1;
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+}
+
+/// 按key的顺序归并多个有序的来源，sources从新到旧排列，同一个key只返回最新的记录
+struct MergeIter<'a> {
+    sources: Vec<(TableIter<'a>, Option<Entry>)>,
+}
+
+impl<'a> MergeIter<'a> {
+    fn new(sources: Vec<TableIter<'a>>) -> Result<Self> {
+        let sources = sources
+            .into_iter()
+            .map(|mut source| source.next().map(|head| (source, head)))
+            .collect::<Result<_>>()?;
+        Ok(MergeIter { sources })
+    }
+
+    fn next(&mut self) -> Result<Option<Entry>> {
+        let min_key = match self
+            .sources
+            .iter()
+            .filter_map(|(_, head)| head.as_ref().map(|(key, _)| key))
+            .min()
+        {
+            Some(key) => key.clone(),
+            None => return Ok(None),
+        };
+        let mut newest = None;
+        for (source, head) in &mut self.sources {
+            if head.as_ref().is_some_and(|(key, _)| *key == min_key) {
+                let entry = std::mem::replace(head, source.next()?);
+                if newest.is_none() {
+                    newest = entry;
+                }
+            }
+        }
+        Ok(newest)
+    }
+}
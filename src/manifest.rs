@@ -0,0 +1,142 @@
+use crate::kv::FileUsage;
+use crate::{CompactionStats, Compression, KvErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{rename, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+/// current on-disk format version
+/// 2: values are written as `Put` records with a raw payload
+/// 3: records may carry a column family and an expiry, `Batch` records group writes
+/// 4: `Append` records extend the value of an earlier record
+/// 5: `Merge` records hold operands of the merge operator
+/// 6: values written by `set_reader` are flagged
+pub(crate) const FORMAT_VERSION: u32 = 6;
+
+/// Describes the layout of a store directory.
+/// Only the data files listed here are live, any other data file in the directory
+/// is deleted by `open`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct Manifest {
+    pub version: u32,
+    /// storage engine that owns the directory
+    pub engine: String,
+    /// id of the next data file to create
+    pub next_file_id: u64,
+    /// live data file ids, in replay order
+    pub files: Vec<u64>,
+    /// SSTable ids of every level of the LSM engine, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub levels: Vec<Vec<u64>>,
+    #[serde(default)]
+    pub compaction: CompactionStats,
+    /// a known value encrypted with the store key, to reject a wrong key on open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<Vec<u8>>,
+    /// last checkpoint of the on-disk index, see `IndexMode::Disk`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<IndexCheckpoint>,
+    /// column families by name, records of a family not listed here are garbage
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub families: BTreeMap<String, FamilyConfig>,
+    /// id of the next column family to create, ids are never reused
+    #[serde(default)]
+    pub next_family_id: u64,
+    /// highest sequence number written when the manifest was saved.
+    /// Compaction may drop the record that carried it, so it is not always in the data files.
+    #[serde(default)]
+    pub last_sequence: u64,
+    /// highest sequence number of a tombstone dropped by compaction,
+    /// `watch_from` can not resume from an earlier sequence number
+    #[serde(default)]
+    pub compacted_sequence: u64,
+    /// name of the merge operator of the `Merge` records in the data files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge_operator: Option<String>,
+}
+
+/// A column family and its options
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct FamilyConfig {
+    /// id stored in the records of the family
+    pub id: u64,
+    pub ttl_millis: Option<u64>,
+    pub compression: Compression,
+}
+
+/// An index file and the state of the store it covers
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct IndexCheckpoint {
+    /// id of the index file
+    pub id: u64,
+    /// the index covers every record before this position of this data file
+    pub file_id: u64,
+    pub offset: u64,
+    pub live_keys: u64,
+    pub file_stats: BTreeMap<u64, FileUsage>,
+}
+
+impl Manifest {
+    pub fn new(engine: &str) -> Self {
+        Manifest {
+            version: FORMAT_VERSION,
+            engine: engine.to_owned(),
+            next_file_id: 0,
+            files: Vec::new(),
+            levels: Vec::new(),
+            compaction: CompactionStats::default(),
+            key_check: None,
+            index: None,
+            families: BTreeMap::new(),
+            next_family_id: 0,
+            last_sequence: 0,
+            compacted_sequence: 0,
+            merge_operator: None,
+        }
+    }
+
+    pub fn has_family(&self, id: u64) -> bool {
+        self.families.values().any(|family| family.id == id)
+    }
+
+    /// Load the manifest of a directory, `None` if it has not been written yet
+    pub fn load(dir_path: &Path, engine: &str) -> Result<Option<Manifest>> {
+        let file = match File::open(dir_path.join(MANIFEST_FILE)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut manifest: Manifest = serde_json::from_reader(BufReader::new(file))?;
+        if manifest.version > FORMAT_VERSION {
+            return Err(KvErr::InvalidManifest(format!(
+                "unsupported format version {}",
+                manifest.version
+            )));
+        }
+        if manifest.engine != engine {
+            return Err(KvErr::InvalidManifest(format!(
+                "directory belongs to engine {}",
+                manifest.engine
+            )));
+        }
+        // 旧版本的格式可以直接读取，之后保存时按当前版本写入
+        manifest.version = FORMAT_VERSION;
+        Ok(Some(manifest))
+    }
+
+    /// 原子地更新manifest：先写临时文件并fsync，再rename覆盖旧文件，
+    /// 最后fsync目录保证rename落盘。任何时刻崩溃，读到的都是完整的旧版本或新版本
+    pub fn save(&self, dir_path: &Path) -> Result<()> {
+        let tmp_path = dir_path.join(MANIFEST_TMP_FILE);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        rename(&tmp_path, dir_path.join(MANIFEST_FILE))?;
+        File::open(dir_path)?.sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+use crate::lru::LruCache;
+use crate::{KvErr, Result};
+
+/// In-memory store with the same semantics as `KvStore`, nothing is written to disk.
+/// Useful for tests and, with a size cap, as an ephemeral cache.
+///
+/// 没有上限时容量是`u64::MAX`，不会淘汰；有上限时按key和value的字节数淘汰最久没用过的key
+pub struct MemStore {
+    entries: LruCache<String, String>,
+}
+
+impl MemStore {
+    /// Create an empty store without a size cap
+    pub fn new() -> MemStore {
+        MemStore {
+            entries: LruCache::new(u64::MAX),
+        }
+    }
+
+    /// Create an empty store holding at most `max_bytes` of keys and values.
+    /// The least recently used keys are evicted to make room for new writes.
+    pub fn with_capacity(max_bytes: u64) -> MemStore {
+        MemStore {
+            entries: LruCache::new(max_bytes),
+        }
+    }
+
+    /// Get the string value of a string key. If the key does not exist, return None.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.entries.get(&key).cloned())
+    }
+
+    /// Set the value of a string key to a string.
+    /// A pair larger than the size cap is not stored.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let weight = (key.len() + value.len()) as u64;
+        self.entries.insert(key, value, weight);
+        Ok(())
+    }
+
+    /// Remove a given key.
+    /// Return an error if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.entries.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvErr::KeyNotFound),
+        }
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,20 @@
+use std::fmt;
+
+/// User-defined read-modify-write operation, registered with `Options::merge_operator`.
+///
+/// `KvStore::merge` only logs its operand, operands are folded into the value by `get`
+/// and written back as a plain value by compaction.
+pub trait MergeOperator: Send + Sync {
+    /// Name of the operator, stored in the manifest of stores with merge operands
+    fn name(&self) -> &str;
+
+    /// Combine the value of a key, `None` if the key does not exist, with an operand.
+    /// Operands are applied in the order they were written.
+    fn merge(&self, key: &str, existing: Option<&str>, operand: &str) -> String;
+}
+
+impl fmt::Debug for dyn MergeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MergeOperator").field(&self.name()).finish()
+    }
+}
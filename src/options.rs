@@ -0,0 +1,71 @@
+use crate::bloom::DEFAULT_FP_RATE;
+use crate::{Compression, EncryptionKey, KvErr, MergeOperator, Result};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Options used to open a `KvStore` with `KvStore::open_with`, or a `LsmStore`
+/// with `LsmStore::open_with`
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// open the store read-only, see `KvStore::open_read_only`
+    pub read_only: bool,
+    /// read immutable data files through a memory map instead of buffered reads
+    pub mmap_reads: bool,
+    /// compression of values written by this handle, records written with
+    /// any other compression stay readable
+    pub compression: Compression,
+    /// encrypt values written by this handle with ChaCha20-Poly1305.
+    /// Once a store has been written with a key, it can only be opened with the same key.
+    /// A store that already holds unencrypted data cannot be opened with a key.
+    pub encryption_key: Option<EncryptionKey>,
+    /// where the key index is kept
+    pub index_mode: IndexMode,
+    /// false positive rate of the Bloom filters written next to sorted index files and
+    /// SSTables, between 0 and 1 exclusive. `None` uses 1%.
+    pub bloom_fp_rate: Option<f64>,
+    /// byte budget of the cache of recently read values, 0 disables the cache
+    pub value_cache_bytes: u64,
+    /// operator folding the operands written by `KvStore::merge` into values.
+    /// Once a store has merge operands, it can only be written with an operator of the same name.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl Options {
+    /// 检查Bloom filter的false positive rate
+    pub(crate) fn fp_rate(&self) -> Result<f64> {
+        match self.bloom_fp_rate {
+            None => Ok(DEFAULT_FP_RATE),
+            Some(rate) if rate > 0.0 && rate < 1.0 => Ok(rate),
+            Some(rate) => Err(KvErr::InvalidOptions(format!(
+                "bloom_fp_rate {} is not between 0 and 1",
+                rate
+            ))),
+        }
+    }
+}
+
+/// Options of a column family, see `KvStore::create_family`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FamilyOptions {
+    /// values written to the family expire this long after they are written,
+    /// `None` keeps them until they are removed
+    pub ttl: Option<Duration>,
+    /// compression of values written to the family
+    pub compression: Compression,
+}
+
+/// Where a `KvStore` keeps its key index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexMode {
+    /// every key is kept in a hash map, rebuilt from the data files on open
+    #[default]
+    Memory,
+    /// keys are kept in a sorted, paged index file that is checkpointed as the store
+    /// is written. Only the writes since the last checkpoint, the first key of every
+    /// page and up to `cache_pages` pages stay in memory, so a `get` takes at most
+    /// one index page read and one data read.
+    Disk {
+        /// number of index pages cached in memory
+        cache_pages: usize,
+    },
+}
@@ -0,0 +1,33 @@
+use crate::Commands;
+use serde::{Deserialize, Serialize};
+
+/// A request sent by the client, tagged with an id chosen by the client.
+/// Several requests may be in flight on one connection at the same time.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Request {
+    /// request id, echoed back in the matching response
+    pub id: u64,
+    /// command to execute on the server
+    pub cmd: Commands,
+}
+
+/// A response sent by the server, carrying the id of the request it answers.
+/// Responses may arrive in a different order than their requests were sent.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Response {
+    /// id of the request this response answers
+    pub id: u64,
+    /// result of the command
+    pub reply: Reply,
+}
+
+/// Result of a single command
+#[derive(Deserialize, Serialize, Debug)]
+pub enum Reply {
+    /// value of a `get`, `None` if the key does not exist
+    Value(Option<String>),
+    /// a `set` or `rm` succeeded
+    Ok,
+    /// the command failed, with the error message
+    Err(String),
+}
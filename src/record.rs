@@ -0,0 +1,812 @@
+use crate::{KvErr, Options, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+
+/// payload is compressed with LZ4
+const FLAG_LZ4: u8 = 1;
+/// payload is compressed with zstd
+const FLAG_ZSTD: u8 = 1 << 1;
+/// payload is encrypted, after compression
+const FLAG_ENCRYPTED: u8 = 1 << 2;
+/// payload is a sequence of separately encrypted chunks, written by `set_reader`
+const FLAG_CHUNKED: u8 = 1 << 3;
+/// value was written by `set_reader`, watchers are not sent its bytes
+const FLAG_STREAM: u8 = 1 << 4;
+/// ChaCha20-Poly1305 nonce, stored in front of every encrypted payload
+const NONCE_LEN: usize = 12;
+/// ChaCha20-Poly1305 authentication tag appended to every ciphertext
+const TAG_LEN: usize = 16;
+/// plaintext size of a chunk of a streamed value
+const CHUNK_SIZE: usize = 64 * 1024;
+/// plaintext encrypted into the manifest to check the key on open
+const KEY_CHECK: &[u8] = b"kvs key check";
+
+/// Compression applied to values written by `set`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Compression {
+    /// values are stored as is
+    #[default]
+    None,
+    /// fast compression, moderate ratio
+    Lz4,
+    /// slower compression, better ratio
+    Zstd,
+}
+
+/// 256-bit key used to encrypt values at rest
+#[derive(Clone)]
+pub struct EncryptionKey(pub [u8; 32]);
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Compression and encryption applied to values, built from the store options
+#[derive(Clone)]
+pub(crate) struct Codec {
+    compression: Compression,
+    cipher: Option<ChaCha20Poly1305>,
+}
+
+impl Codec {
+    pub fn new(options: &Options) -> Self {
+        Codec {
+            compression: options.compression,
+            cipher: options
+                .encryption_key
+                .as_ref()
+                .map(|key| ChaCha20Poly1305::new(&key.0.into())),
+        }
+    }
+
+    /// Same encryption, values are compressed with `compression`
+    pub fn with_compression(&self, compression: Compression) -> Codec {
+        Codec {
+            compression,
+            ..self.clone()
+        }
+    }
+
+    /// 用随机nonce加密，`aad`是associated data，由`header_aad`生成
+    fn encrypt(&self, cipher: &ChaCha20Poly1305, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| KvErr::Decryption)?;
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+        Ok(payload)
+    }
+
+    fn decrypt(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.cipher.as_ref().ok_or(KvErr::Decryption)?;
+        if data.len() < NONCE_LEN {
+            return Err(KvErr::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| KvErr::Decryption)
+    }
+
+    /// associated data包含整个record header：记录的类型、key、flags、payload长度、
+    /// column family、过期时间、seq和delta链的prev，
+    /// 把payload换到另一条记录下面，或者改了header的任何字段，都会解密失败
+    fn header_aad(header: &Record) -> Vec<u8> {
+        let (kind, key, flags, prev) = match header {
+            Record::Set { key, .. } => (b'S', key.as_str(), 0, None),
+            Record::Rm { key, .. } => (b'R', key.as_str(), 0, None),
+            Record::Put { key, flags, .. } => (b'P', key.as_str(), *flags, None),
+            Record::Append {
+                key, flags, prev, ..
+            } => (b'A', key.as_str(), *flags, *prev),
+            Record::Merge {
+                key, flags, prev, ..
+            } => (b'M', key.as_str(), *flags, *prev),
+            Record::Batch { .. } => (b'B', "", 0, None),
+        };
+        let meta = header.meta();
+        let mut aad = vec![kind];
+        aad.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        aad.extend_from_slice(key.as_bytes());
+        aad.push(flags);
+        aad.extend_from_slice(&header.payload_len().to_le_bytes());
+        for field in [meta.cf, meta.expires, meta.seq, prev] {
+            match field {
+                Some(value) => {
+                    aad.push(1);
+                    aad.extend_from_slice(&value.to_le_bytes());
+                }
+                None => aad.push(0),
+            }
+        }
+        aad
+    }
+
+    /// 流式写入的value按CHUNK_SIZE分块加密，associated data在header之外还包含块的序号和
+    /// 是否为最后一块，块被调换顺序或者value被截断都会解密失败
+    fn chunk_aad(header_aad: &[u8], index: u64, last: bool) -> Vec<u8> {
+        let mut aad = header_aad.to_vec();
+        aad.extend_from_slice(&index.to_le_bytes());
+        aad.push(last as u8);
+        aad
+    }
+
+    /// 有key的store里所有payload都是加密的，没有加密的记录只可能是被篡改或者注入的
+    fn check_plain(&self) -> Result<()> {
+        match self.cipher {
+            Some(_) => Err(KvErr::Decryption),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether values are encrypted
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Value stored in the manifest to detect a wrong key on open,
+    /// `None` without encryption
+    pub fn key_check(&self) -> Result<Option<Vec<u8>>> {
+        match &self.cipher {
+            Some(cipher) => Ok(Some(self.encrypt(cipher, b"", KEY_CHECK)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Check the key against the value stored in the manifest
+    pub fn verify_key(&self, key_check: &[u8]) -> Result<()> {
+        if self.decrypt(b"", key_check)? == KEY_CHECK {
+            Ok(())
+        } else {
+            Err(KvErr::Decryption)
+        }
+    }
+}
+
+/// A record of a data file.
+///
+/// 记录由一个JSON头组成，`Put`的JSON头后面紧跟着`len`字节的payload，
+/// payload可能被压缩，用`flags`区分。`Set`是旧版本写入的记录，value直接放在JSON中，
+/// 两种记录可以共存在同一个文件中。
+/// `Batch`的JSON头后面紧跟着`len`字节的若干条记录，它们要么全部有效，要么全部无效。
+/// 默认column family的记录没有`cf`字段，和旧版本写入的记录相同
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) enum Record {
+    Set {
+        key: String,
+        value: String,
+    },
+    /// in an encrypted store the header is followed by `len` bytes, an empty payload
+    /// encrypted to authenticate the header
+    Rm {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cf: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        len: Option<u64>,
+    },
+    Put {
+        key: String,
+        flags: u8,
+        len: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cf: Option<u64>,
+        /// unix time in milliseconds after which the value is gone
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// suffix appended to the value of the record at `prev` in the same data file,
+    /// or to an empty value when `prev` is `None`
+    Append {
+        key: String,
+        flags: u8,
+        len: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prev: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    /// operand of the merge operator, applied like an `Append` suffix
+    Merge {
+        key: String,
+        flags: u8,
+        len: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prev: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    Batch {
+        len: u64,
+    },
+}
+
+/// Column family, expiry and sequence number of a record
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RecordMeta {
+    /// id of the column family, `None` for the default family
+    pub cf: Option<u64>,
+    pub expires: Option<u64>,
+    /// sequence number of the write, records written before sequence numbers have none
+    pub seq: Option<u64>,
+}
+
+/// Kind of a delta record, a chain of them is folded into the value on read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Delta {
+    /// the operand is appended to the value
+    Append,
+    /// the operand is passed to the merge operator with the value
+    Merge,
+}
+
+impl Record {
+    /// Encode a `Put` record of the value into `buf`.
+    /// The value is stored uncompressed when compression does not make it smaller,
+    /// then encrypted if the store has a key.
+    pub fn encode_put(
+        key: &str,
+        value: &[u8],
+        meta: RecordMeta,
+        codec: &Codec,
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        let (flags, payload, len) = Self::encode_payload(value, codec)?;
+        let header = Record::Put {
+            key: key.to_owned(),
+            flags,
+            len,
+            cf: meta.cf,
+            expires: meta.expires,
+            seq: meta.seq,
+        };
+        Self::write_record(&header, &payload, codec, buf)
+    }
+
+    /// Encode a delta record of the operand into `buf`, `prev` is the position of
+    /// the previous record of the key in the same data file
+    pub fn encode_delta(
+        delta: Delta,
+        key: &str,
+        operand: &[u8],
+        prev: Option<u64>,
+        seq: u64,
+        codec: &Codec,
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        let (flags, payload, len) = Self::encode_payload(operand, codec)?;
+        let (key, seq) = (key.to_owned(), Some(seq));
+        let header = match delta {
+            Delta::Append => Record::Append {
+                key,
+                flags,
+                len,
+                prev,
+                seq,
+            },
+            Delta::Merge => Record::Merge {
+                key,
+                flags,
+                len,
+                prev,
+                seq,
+            },
+        };
+        Self::write_record(&header, &payload, codec, buf)
+    }
+
+    /// 压缩之后没有变小就不压缩，返回flags、没有加密的payload和写入文件的payload长度，
+    /// 有key时payload在header确定之后由`write_record`加密
+    fn encode_payload<'a>(value: &'a [u8], codec: &Codec) -> Result<(u8, Cow<'a, [u8]>, u64)> {
+        let compressed = match codec.compression {
+            Compression::None => None,
+            Compression::Lz4 => Some((FLAG_LZ4, lz4_flex::compress_prepend_size(value))),
+            Compression::Zstd => Some((FLAG_ZSTD, zstd::encode_all(value, 0)?)),
+        };
+        let (flags, payload) = match compressed {
+            Some((flags, payload)) if payload.len() < value.len() => (flags, Cow::Owned(payload)),
+            _ => (0, Cow::Borrowed(value)),
+        };
+        match codec.cipher {
+            Some(_) => {
+                let len = (NONCE_LEN + payload.len() + TAG_LEN) as u64;
+                Ok((flags | FLAG_ENCRYPTED, payload, len))
+            }
+            None => {
+                let len = payload.len() as u64;
+                Ok((flags, payload, len))
+            }
+        }
+    }
+
+    /// 写入header和payload，有key时用header生成的associated data加密payload
+    fn write_record(
+        header: &Record,
+        payload: &[u8],
+        codec: &Codec,
+        buf: &mut Vec<u8>,
+    ) -> Result<()> {
+        serde_json::to_writer(&mut *buf, header)?;
+        match &codec.cipher {
+            Some(cipher) => {
+                let sealed = codec.encrypt(cipher, &Codec::header_aad(header), payload)?;
+                buf.extend_from_slice(&sealed);
+            }
+            None => buf.extend_from_slice(payload),
+        }
+        Ok(())
+    }
+
+    /// Write a `Put` record of `len` bytes read from `reader`, returns the record length.
+    /// The value is copied in chunks and never compressed, it is encrypted chunk by
+    /// chunk if the store has a key.
+    pub fn write_stream(
+        key: &str,
+        reader: impl Read,
+        len: u64,
+        meta: RecordMeta,
+        codec: &Codec,
+        writer: &mut impl Write,
+    ) -> Result<u64> {
+        let chunks = len.div_ceil(CHUNK_SIZE as u64).max(1);
+        let (flags, payload_len) = match codec.cipher {
+            Some(_) => (
+                FLAG_STREAM | FLAG_ENCRYPTED | FLAG_CHUNKED,
+                len + chunks * (NONCE_LEN + TAG_LEN) as u64,
+            ),
+            None => (FLAG_STREAM, len),
+        };
+        let header = Record::Put {
+            key: key.to_owned(),
+            flags,
+            len: payload_len,
+            cf: meta.cf,
+            expires: meta.expires,
+            seq: meta.seq,
+        };
+        let header_len = {
+            let encoded = serde_json::to_vec(&header)?;
+            writer.write_all(&encoded)?;
+            encoded.len() as u64
+        };
+        let mut reader = reader.take(len);
+        match &codec.cipher {
+            Some(cipher) => {
+                let header_aad = Codec::header_aad(&header);
+                let mut buf = vec![0; CHUNK_SIZE];
+                let mut remaining = len;
+                for index in 0..chunks {
+                    let size = remaining.min(CHUNK_SIZE as u64) as usize;
+                    reader.read_exact(&mut buf[..size])?;
+                    remaining -= size as u64;
+                    let aad = Codec::chunk_aad(&header_aad, index, index + 1 == chunks);
+                    writer.write_all(&codec.encrypt(cipher, &aad, &buf[..size])?)?;
+                }
+            }
+            None => {
+                if io::copy(&mut reader, writer)? != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+        }
+        Ok(header_len + payload_len)
+    }
+
+    /// Encode a `Rm` record into `buf`, with an authentication tag if the store has a key
+    pub fn encode_rm(key: &str, meta: RecordMeta, codec: &Codec, buf: &mut Vec<u8>) -> Result<()> {
+        let header = Record::Rm {
+            key: key.to_owned(),
+            cf: meta.cf,
+            seq: meta.seq,
+            len: codec.cipher.as_ref().map(|_| (NONCE_LEN + TAG_LEN) as u64),
+        };
+        Self::write_record(&header, b"", codec, buf)
+    }
+
+    /// Read the payload following the header of a `Rm` record and check its
+    /// authentication tag. In an encrypted store a tombstone without a valid tag
+    /// was injected or tampered with.
+    pub fn check_tombstone(&self, reader: &mut impl Read, codec: &Codec) -> Result<()> {
+        let len = self.payload_len();
+        if len == 0 {
+            return codec.check_plain();
+        }
+        let mut payload = vec![0; len as usize];
+        reader.read_exact(&mut payload)?;
+        let value = codec.decrypt(&Codec::header_aad(self), &payload)?;
+        if value.is_empty() {
+            Ok(())
+        } else {
+            Err(KvErr::Decryption)
+        }
+    }
+
+    /// Encode a `Batch` record of the records encoded in `records`
+    pub fn encode_batch(records: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+        let header = Record::Batch {
+            len: records.len() as u64,
+        };
+        serde_json::to_writer(&mut *buf, &header)?;
+        buf.extend_from_slice(records);
+        Ok(())
+    }
+
+    /// Column family, expiry and sequence number of the record
+    pub fn meta(&self) -> RecordMeta {
+        match self {
+            Record::Put {
+                cf, expires, seq, ..
+            } => RecordMeta {
+                cf: *cf,
+                expires: *expires,
+                seq: *seq,
+            },
+            Record::Rm { cf, seq, .. } => RecordMeta {
+                cf: *cf,
+                expires: None,
+                seq: *seq,
+            },
+            Record::Append { seq, .. } | Record::Merge { seq, .. } => RecordMeta {
+                seq: *seq,
+                ..RecordMeta::default()
+            },
+            _ => RecordMeta::default(),
+        }
+    }
+
+    /// Key of the record, empty for a `Batch` record
+    pub fn into_key(self) -> String {
+        match self {
+            Record::Set { key, .. }
+            | Record::Rm { key, .. }
+            | Record::Put { key, .. }
+            | Record::Append { key, .. }
+            | Record::Merge { key, .. } => key,
+            Record::Batch { .. } => String::new(),
+        }
+    }
+
+    /// Whether the record is a value written by `set_reader`
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Record::Put { flags, .. } if flags & FLAG_STREAM != 0)
+    }
+
+    /// Kind of a delta record and the position of the previous record of its chain
+    pub fn delta(&self) -> Option<(Delta, Option<u64>)> {
+        match self {
+            Record::Append { prev, .. } => Some((Delta::Append, *prev)),
+            Record::Merge { prev, .. } => Some((Delta::Merge, *prev)),
+            _ => None,
+        }
+    }
+
+    /// Decode the value of a complete `Set` or `Put` record.
+    /// A plain payload is borrowed from `data` without copying.
+    pub fn decode_value<'a>(data: &'a [u8], codec: &Codec) -> Result<Cow<'a, [u8]>> {
+        let mut iter = serde_json::Deserializer::from_slice(data).into_iter::<Record>();
+        let header = iter.next().ok_or(KvErr::UnknownCommand)??;
+        let payload = &data[iter.byte_offset()..];
+        match header {
+            Record::Set { value, .. } => {
+                codec.check_plain()?;
+                Ok(Cow::Owned(value.into_bytes()))
+            }
+            Record::Put { flags, len, .. } if payload.len() as u64 == len => {
+                Self::decode_payload(&header, flags, payload, codec)
+            }
+            _ => Err(KvErr::UnknownCommand),
+        }
+    }
+
+    /// 解码`header`后面的payload，`flags`是header中的flags
+    fn decode_payload<'a>(
+        header: &Record,
+        flags: u8,
+        payload: &'a [u8],
+        codec: &Codec,
+    ) -> Result<Cow<'a, [u8]>> {
+        if flags & FLAG_CHUNKED != 0 {
+            let mut value = Vec::new();
+            ChunkReader::new(payload, codec, header)?
+                .read_to_end(&mut value)
+                .map_err(|_| KvErr::Decryption)?;
+            return Ok(Cow::Owned(value));
+        }
+        let payload = if flags & FLAG_ENCRYPTED != 0 {
+            Cow::Owned(codec.decrypt(&Codec::header_aad(header), payload)?)
+        } else {
+            codec.check_plain()?;
+            Cow::Borrowed(payload)
+        };
+        match flags & !(FLAG_ENCRYPTED | FLAG_STREAM) {
+            0 => Ok(payload),
+            FLAG_LZ4 => lz4_flex::decompress_size_prepended(&payload)
+                .map(Cow::Owned)
+                .map_err(|err| KvErr::Corrupted(err.to_string())),
+            FLAG_ZSTD => Ok(Cow::Owned(zstd::decode_all(&payload[..])?)),
+            _ => Err(KvErr::Corrupted(format!("unknown record flags {}", flags))),
+        }
+    }
+
+    /// Open a reader over the value of the record at the position of `reader`
+    pub fn open_value(mut reader: BufReader<File>, codec: &Codec) -> Result<ValueReader> {
+        let (header, _) = Self::read_header(&mut reader)?.ok_or(KvErr::UnknownCommand)?;
+        let inner = match header {
+            Record::Set { value, .. } => {
+                codec.check_plain()?;
+                ValueSource::Memory(io::Cursor::new(value.into_bytes()))
+            }
+            Record::Put { flags, len, .. } if flags & !FLAG_STREAM == 0 => {
+                codec.check_plain()?;
+                ValueSource::File(reader.take(len))
+            }
+            Record::Put { flags, len, .. } if flags & FLAG_CHUNKED != 0 => {
+                ValueSource::Chunks(ChunkReader::new(reader.take(len), codec, &header)?)
+            }
+            Record::Put { flags, len, .. } => {
+                // 压缩或者整体加密的value只能整体解码
+                let mut payload = vec![0; len as usize];
+                reader.read_exact(&mut payload)?;
+                let value = Self::decode_payload(&header, flags, &payload, codec)?.into_owned();
+                ValueSource::Memory(io::Cursor::new(value))
+            }
+            Record::Rm { .. }
+            | Record::Append { .. }
+            | Record::Merge { .. }
+            | Record::Batch { .. } => return Err(KvErr::UnknownCommand),
+        };
+        Ok(ValueReader { inner })
+    }
+
+    /// Decode a value into a `String`
+    pub fn decode_string(data: &[u8], codec: &Codec) -> Result<String> {
+        let value = Self::decode_value(data, codec)?.into_owned();
+        String::from_utf8(value).map_err(|err| KvErr::Corrupted(err.to_string()))
+    }
+
+    /// Read the payload following a header returned by `read_header`,
+    /// returns the key and its value, the operand of a delta record or `None` for a `Rm` record
+    pub fn read_value(
+        self,
+        reader: &mut impl Read,
+        codec: &Codec,
+    ) -> Result<(String, Option<String>)> {
+        let (key, value) = self.read_bytes(reader, codec)?;
+        let value = value
+            .map(|value| String::from_utf8(value).map_err(|err| KvErr::Corrupted(err.to_string())))
+            .transpose()?;
+        Ok((key, value))
+    }
+
+    /// Like `read_value`, without checking that the value is UTF-8
+    pub fn read_bytes(
+        self,
+        reader: &mut impl Read,
+        codec: &Codec,
+    ) -> Result<(String, Option<Vec<u8>>)> {
+        match self {
+            Record::Set { key, value } => {
+                codec.check_plain()?;
+                Ok((key, Some(value.into_bytes())))
+            }
+            Record::Rm { .. } => {
+                self.check_tombstone(reader, codec)?;
+                Ok((self.into_key(), None))
+            }
+            Record::Batch { .. } => Err(KvErr::Corrupted("unexpected batch record".to_owned())),
+            Record::Put { flags, len, .. }
+            | Record::Append { flags, len, .. }
+            | Record::Merge { flags, len, .. } => {
+                let mut payload = vec![0; len as usize];
+                reader.read_exact(&mut payload)?;
+                let value = Self::decode_payload(&self, flags, &payload, codec)?.into_owned();
+                Ok((self.into_key(), Some(value)))
+            }
+        }
+    }
+
+    /// Read the header of the next record, returns the record and its header length.
+    /// The payload of a `Put` record is left in the reader.
+    /// Returns `None` at the end of the reader.
+    pub fn read_header(reader: &mut impl Read) -> serde_json::Result<Option<(Record, u64)>> {
+        let mut counter = CountingReader {
+            inner: reader,
+            count: 0,
+        };
+        // 每条记录用一个新的Deserializer解析，它读到JSON结束的`}`为止，不会多读后面的payload
+        let mut de = serde_json::Deserializer::from_reader(&mut counter);
+        match Record::deserialize(&mut de) {
+            Ok(record) => Ok(Some((record, counter.count))),
+            Err(err) if err.is_eof() && counter.count == 0 => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Length of the payload following the header
+    pub fn payload_len(&self) -> u64 {
+        match self {
+            Record::Put { len, .. }
+            | Record::Append { len, .. }
+            | Record::Merge { len, .. }
+            | Record::Batch { len } => *len,
+            Record::Rm { len, .. } => len.unwrap_or(0),
+            Record::Set { .. } => 0,
+        }
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
+/// Reader over a value, returned by `KvStore::get_reader`.
+/// Values written uncompressed are read from the data file chunk by chunk.
+pub struct ValueReader {
+    inner: ValueSource,
+}
+
+enum ValueSource {
+    File(io::Take<BufReader<File>>),
+    Chunks(ChunkReader<io::Take<BufReader<File>>>),
+    Memory(io::Cursor<Vec<u8>>),
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            ValueSource::File(reader) => reader.read(buf),
+            ValueSource::Chunks(reader) => reader.read(buf),
+            ValueSource::Memory(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl ValueReader {
+    /// 读取已经在内存中的value，例如append链拼接的结果
+    pub(crate) fn memory(value: Vec<u8>) -> ValueReader {
+        ValueReader {
+            inner: ValueSource::Memory(io::Cursor::new(value)),
+        }
+    }
+
+    /// 跳过value的前`len`个字节，文件中的value直接seek，分块加密的value从所在的块开始解密
+    pub(crate) fn skip(&mut self, len: u64) -> io::Result<()> {
+        match &mut self.inner {
+            ValueSource::File(reader) => skip_take(reader, len),
+            ValueSource::Chunks(reader) => reader.skip(len),
+            ValueSource::Memory(reader) => {
+                reader.set_position(reader.position().saturating_add(len));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// 在value的范围内向后seek，不超过value的结尾
+fn skip_take(reader: &mut io::Take<BufReader<File>>, len: u64) -> io::Result<()> {
+    let len = len.min(reader.limit());
+    reader.get_mut().seek_relative(len as i64)?;
+    reader.set_limit(reader.limit() - len);
+    Ok(())
+}
+
+/// 逐块解密`write_stream`写入的加密payload
+struct ChunkReader<R> {
+    inner: R,
+    codec: Codec,
+    /// associated data of the record header
+    header_aad: Vec<u8>,
+    index: u64,
+    chunks: u64,
+    /// encrypted bytes of the payload not read yet
+    remaining: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> ChunkReader<R> {
+    fn new(inner: R, codec: &Codec, header: &Record) -> Result<Self> {
+        let sealed = (CHUNK_SIZE + NONCE_LEN + TAG_LEN) as u64;
+        let len = header.payload_len();
+        Ok(ChunkReader {
+            inner,
+            codec: Codec {
+                compression: Compression::None,
+                cipher: Some(codec.cipher.clone().ok_or(KvErr::Decryption)?),
+            },
+            header_aad: Codec::header_aad(header),
+            index: 0,
+            chunks: len.div_ceil(sealed),
+            remaining: len,
+            chunk: Vec::new(),
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read> ChunkReader<R> {
+    /// 读取并解密下一块
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let size = self
+            .remaining
+            .min((CHUNK_SIZE + NONCE_LEN + TAG_LEN) as u64) as usize;
+        let mut sealed = vec![0; size];
+        self.inner.read_exact(&mut sealed)?;
+        self.remaining -= size as u64;
+        let last = self.index + 1 == self.chunks;
+        let aad = Codec::chunk_aad(&self.header_aad, self.index, last);
+        self.chunk = self
+            .codec
+            .decrypt(&aad, &sealed)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        self.index += 1;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl ChunkReader<io::Take<BufReader<File>>> {
+    /// 每块加密后的长度固定，整块跳过的部分不用读取和解密
+    fn skip(&mut self, mut len: u64) -> io::Result<()> {
+        let buffered = len.min((self.chunk.len() - self.pos) as u64);
+        self.pos += buffered as usize;
+        len -= buffered;
+        if len == 0 {
+            return Ok(());
+        }
+        let sealed = (CHUNK_SIZE + NONCE_LEN + TAG_LEN) as u64;
+        let whole = (len / CHUNK_SIZE as u64).min(self.chunks - self.index);
+        let skipped = (whole * sealed).min(self.remaining);
+        skip_take(&mut self.inner, skipped)?;
+        self.remaining -= skipped;
+        self.index += whole;
+        len -= whole * CHUNK_SIZE as u64;
+        if len > 0 && self.index < self.chunks {
+            self.next_chunk()?;
+            self.pos = (len as usize).min(self.chunk.len());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.index == self.chunks {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
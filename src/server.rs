@@ -0,0 +1,70 @@
+use crate::protocol::{Reply, Request, Response};
+use crate::{Commands, KvsEngine, Result};
+use serde_json::Deserializer;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Key/value server, answers pipelined requests from `KvsClient`.
+/// Every connection is served on its own thread, commands of all connections
+/// take turns on the store.
+pub struct KvsServer<E: KvsEngine> {
+    store: Arc<Mutex<E>>,
+}
+
+impl<E: KvsEngine + Send + 'static> KvsServer<E> {
+    /// Create a server serving the given store
+    pub fn new(store: E) -> Self {
+        KvsServer {
+            store: Arc::new(Mutex::new(store)),
+        }
+    }
+
+    /// Bind to the given address and serve connections forever
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serve connections accepted on an already bound listener
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let store = Arc::clone(&self.store);
+            // 连接出错时客户端会看到连接断开，只影响这一个连接
+            thread::spawn(move || Self::handle(&store, stream));
+        }
+        Ok(())
+    }
+
+    /// 每个连接上的请求依次执行，响应带上请求的id，
+    /// 客户端可以不等响应就继续发送请求。只在执行命令时持有store的锁
+    fn handle(store: &Mutex<E>, stream: TcpStream) -> Result<()> {
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        let requests = Deserializer::from_reader(reader).into_iter::<Request>();
+        for req in requests {
+            let Request { id, cmd } = req?;
+            // 某个连接的线程panic之后，其他连接仍然可以继续使用store
+            let result = Self::execute(
+                &mut store.lock().unwrap_or_else(|err| err.into_inner()),
+                cmd,
+            );
+            let reply = match result {
+                Ok(reply) => reply,
+                Err(err) => Reply::Err(err.to_string()),
+            };
+            serde_json::to_writer(&mut writer, &Response { id, reply })?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn execute(store: &mut E, cmd: Commands) -> Result<Reply> {
+        match cmd {
+            Commands::Get { key } => Ok(Reply::Value(store.get(key)?)),
+            Commands::Set { key, value } => store.set(key, value).map(|_| Reply::Ok),
+            Commands::Rm { key } => store.remove(key).map(|_| Reply::Ok),
+        }
+    }
+}
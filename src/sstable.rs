@@ -0,0 +1,191 @@
+use crate::bloom::{key_hash, Bloom};
+use crate::{KvErr, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{remove_file, rename, File};
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// target size of a page
+const PAGE_SIZE: usize = 4096;
+
+/// first key and location of a page
+#[derive(Deserialize, Serialize, Debug)]
+struct PageRef {
+    first_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// Immutable file of key/value pairs sorted by key.
+///
+/// 文件由若干page组成，每个page是按key排序的`(key, value)`的JSON数组，
+/// 文件末尾是所有page的第一个key和位置（稀疏索引），最后8个字节是稀疏索引的长度。
+/// 打开文件只读取稀疏索引和旁边的Bloom filter，查找一个key最多读一个page
+pub(crate) struct Table {
+    file: File,
+    pages: Vec<PageRef>,
+    /// filter of the keys, tables written before filters existed have none
+    bloom: Option<Bloom>,
+}
+
+impl Table {
+    pub fn open(path: &Path) -> Result<Table> {
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let truncated = || KvErr::Corrupted(format!("truncated table {}", path.display()));
+        if file_len < 8 {
+            return Err(truncated());
+        }
+        let mut len = [0; 8];
+        file.seek(SeekFrom::Start(file_len - 8))?;
+        file.read_exact(&mut len)?;
+        let footer_len = u64::from_le_bytes(len);
+        if footer_len > file_len - 8 {
+            return Err(truncated());
+        }
+        let mut footer = vec![0; footer_len as usize];
+        file.seek(SeekFrom::Start(file_len - 8 - footer_len))?;
+        file.read_exact(&mut footer)?;
+        Ok(Table {
+            file,
+            pages: serde_json::from_slice(&footer)?,
+            bloom: Bloom::load(&bloom_path(path))?,
+        })
+    }
+
+    /// `false` if the key is certainly not in the table
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bloom
+            .as_ref()
+            .is_none_or(|bloom| bloom.may_contain(key))
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// The page that may contain `key`, `None` if the key is before the first page
+    pub fn find_page(&self, key: &str) -> Option<usize> {
+        match self
+            .pages
+            .partition_point(|page| page.first_key.as_str() <= key)
+        {
+            0 => None,
+            next => Some(next - 1),
+        }
+    }
+
+    pub fn read_page<V: DeserializeOwned>(&self, page_no: usize) -> Result<Vec<(String, V)>> {
+        let page = &self.pages[page_no];
+        let mut buf = vec![0; page.len as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(page.offset))?;
+        file.read_exact(&mut buf)?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+}
+
+/// Find a key in a page read by `Table::read_page`
+pub(crate) fn search_page<'a, V>(page: &'a [(String, V)], key: &str) -> Option<&'a V> {
+    page.binary_search_by(|(page_key, _)| page_key.as_str().cmp(key))
+        .ok()
+        .map(|idx| &page[idx].1)
+}
+
+/// Path of the Bloom filter kept next to a table
+pub(crate) fn bloom_path(path: &Path) -> PathBuf {
+    path.with_extension("bloom")
+}
+
+/// Delete a table and its Bloom filter
+pub(crate) fn remove_table(path: &Path) -> Result<()> {
+    // 旧版本写入的table没有filter
+    match remove_file(bloom_path(path)) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    remove_file(path)?;
+    Ok(())
+}
+
+/// 按key的顺序写入一个新的table，写完fsync之后才从`.tmp`文件rename成正式的文件名。
+/// Bloom filter在rename之前写好，有table文件就一定有filter
+pub(crate) struct TableWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    pages: Vec<PageRef>,
+    /// JSON array of the page being filled
+    page: Vec<u8>,
+    first_key: String,
+    key_hashes: Vec<u64>,
+    fp_rate: f64,
+}
+
+impl TableWriter {
+    /// Create a table at `path` with a Bloom filter of the given false positive rate
+    pub fn create(path: &Path, fp_rate: f64) -> Result<Self> {
+        let tmp_path = path.with_extension("tmp");
+        Ok(TableWriter {
+            path: path.to_owned(),
+            writer: BufWriter::new(File::create(&tmp_path)?),
+            tmp_path,
+            offset: 0,
+            pages: Vec::new(),
+            page: Vec::new(),
+            first_key: String::new(),
+            key_hashes: Vec::new(),
+            fp_rate,
+        })
+    }
+
+    /// Append a pair, keys must be pushed in ascending order
+    pub fn push<V: Serialize>(&mut self, key: &str, value: &V) -> Result<()> {
+        let entry = serde_json::to_vec(&(key, value))?;
+        if !self.page.is_empty() && self.page.len() + entry.len() + 1 > PAGE_SIZE {
+            self.flush_page()?;
+        }
+        if self.page.is_empty() {
+            self.page.push(b'[');
+            self.first_key = key.to_owned();
+        } else {
+            self.page.push(b',');
+        }
+        self.page.extend_from_slice(&entry);
+        self.key_hashes.push(key_hash(key));
+        Ok(())
+    }
+
+    fn flush_page(&mut self) -> Result<()> {
+        self.page.push(b']');
+        self.writer.write_all(&self.page)?;
+        self.pages.push(PageRef {
+            first_key: std::mem::take(&mut self.first_key),
+            offset: self.offset,
+            len: self.page.len() as u64,
+        });
+        self.offset += self.page.len() as u64;
+        self.page.clear();
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if !self.page.is_empty() {
+            self.flush_page()?;
+        }
+        let footer = serde_json::to_vec(&self.pages)?;
+        self.writer.write_all(&footer)?;
+        self.writer
+            .write_all(&(footer.len() as u64).to_le_bytes())?;
+        let file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Bloom::build(&self.key_hashes, self.fp_rate).save(&bloom_path(&self.path))?;
+        rename(&self.tmp_path, &self.path)?;
+        if let Some(dir_path) = self.path.parent() {
+            File::open(dir_path)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Statistics of a `KvStore`, returned by `KvStore::stats`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Stats {
+    /// number of live keys
+    pub live_keys: u64,
+    /// bytes of live records, including tombstones that can not be dropped yet
+    pub live_bytes: u64,
+    /// bytes of garbage records, reclaimed by compaction
+    pub dead_bytes: u64,
+    /// per data file statistics, in file id order
+    pub files: Vec<FileStats>,
+    /// compaction history of the store
+    pub compaction: CompactionStats,
+    /// effectiveness of the Bloom filters of on-disk indexes
+    #[serde(default)]
+    pub bloom: BloomStats,
+    /// value cache counters since the store was opened
+    #[serde(default)]
+    pub cache: CacheStats,
+}
+
+/// Statistics of one data file
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FileStats {
+    /// data file id
+    pub file_id: u64,
+    /// size of the file
+    pub total_bytes: u64,
+    /// bytes of garbage records in the file
+    pub dead_bytes: u64,
+}
+
+/// Compaction statistics, persisted in the store manifest
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct CompactionStats {
+    /// number of compactions since the store was created
+    pub compactions: u64,
+    /// duration of the last compaction, in microseconds
+    pub last_duration_micros: u64,
+    /// bytes reclaimed by the last compaction
+    pub last_reclaimed_bytes: u64,
+    /// bytes reclaimed by all compactions
+    pub total_reclaimed_bytes: u64,
+}
+
+/// Bloom filter statistics since the store was opened
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BloomStats {
+    /// lookups that consulted a filter
+    pub checks: u64,
+    /// lookups answered by a filter without reading the file
+    pub skipped_reads: u64,
+    /// lookups the filter let through for a key the file does not have
+    pub false_positives: u64,
+}
+
+/// Value cache statistics, see `Options::value_cache_bytes`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// `get` calls answered from the cache
+    pub hits: u64,
+    /// `get` calls that read the value from disk
+    pub misses: u64,
+    /// bytes of keys and values in the cache
+    pub bytes: u64,
+    /// byte budget of the cache, 0 when the cache is disabled
+    pub capacity_bytes: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "live keys: {}", self.live_keys)?;
+        writeln!(f, "live bytes: {}", self.live_bytes)?;
+        writeln!(f, "dead bytes: {}", self.dead_bytes)?;
+        writeln!(f, "compactions: {}", self.compaction.compactions)?;
+        writeln!(
+            f,
+            "last compaction: {}us, {} bytes reclaimed",
+            self.compaction.last_duration_micros, self.compaction.last_reclaimed_bytes
+        )?;
+        writeln!(
+            f,
+            "total reclaimed bytes: {}",
+            self.compaction.total_reclaimed_bytes
+        )?;
+        writeln!(
+            f,
+            "bloom filters since open: {} checks, {} reads skipped, {} false positives",
+            self.bloom.checks, self.bloom.skipped_reads, self.bloom.false_positives
+        )?;
+        writeln!(
+            f,
+            "value cache since open: {} hits, {} misses, {}/{} bytes",
+            self.cache.hits, self.cache.misses, self.cache.bytes, self.cache.capacity_bytes
+        )?;
+        for file in &self.files {
+            writeln!(
+                f,
+                "file {}: {} bytes, {} dead",
+                file.file_id, file.total_bytes, file.dead_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// A write reported by `KvStore::watch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// the key was set to the value
+    Set {
+        /// sequence number of the write
+        seq: u64,
+        /// key written
+        key: String,
+        /// new value of the key
+        value: String,
+    },
+    /// the key was set by `KvStore::set_reader`, the value is not sent,
+    /// read it with `KvStore::get_reader`
+    SetStream {
+        /// sequence number of the write
+        seq: u64,
+        /// key written
+        key: String,
+    },
+    /// the suffix was appended to the value of the key
+    Append {
+        /// sequence number of the write
+        seq: u64,
+        /// key written
+        key: String,
+        /// bytes appended to the value
+        suffix: String,
+    },
+    /// the operand was logged for the merge operator of the key
+    Merge {
+        /// sequence number of the write
+        seq: u64,
+        /// key written
+        key: String,
+        /// operand passed to `KvStore::merge`
+        operand: String,
+    },
+    /// the key was removed
+    Rm {
+        /// sequence number of the write
+        seq: u64,
+        /// key removed
+        key: String,
+    },
+}
+
+impl WatchEvent {
+    /// Sequence number of the write, pass the last one seen to `KvStore::watch_from`
+    /// to resume watching
+    pub fn seq(&self) -> u64 {
+        match self {
+            WatchEvent::Set { seq, .. }
+            | WatchEvent::SetStream { seq, .. }
+            | WatchEvent::Append { seq, .. }
+            | WatchEvent::Merge { seq, .. }
+            | WatchEvent::Rm { seq, .. } => *seq,
+        }
+    }
+
+    /// Key of the write
+    pub fn key(&self) -> &str {
+        match self {
+            WatchEvent::Set { key, .. }
+            | WatchEvent::SetStream { key, .. }
+            | WatchEvent::Append { key, .. }
+            | WatchEvent::Merge { key, .. }
+            | WatchEvent::Rm { key, .. } => key,
+        }
+    }
+}
+
+/// 每个watcher是一个key前缀和channel的发送端，接收端被drop之后下一次通知时移除
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watchers: Vec<(String, Sender<WatchEvent>)>,
+}
+
+impl Watchers {
+    /// Register a watcher of `prefix`, `backlog` is sent before any new event
+    pub fn add(&mut self, prefix: &str, backlog: Vec<WatchEvent>) -> Receiver<WatchEvent> {
+        let (sender, receiver) = channel();
+        for event in backlog {
+            // 接收端还在这里，发送不会失败
+            let _ = sender.send(event);
+        }
+        self.watchers.push((prefix.to_owned(), sender));
+        receiver
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchers.is_empty()
+    }
+
+    /// Whether someone watches the key
+    pub fn watches(&self, key: &str) -> bool {
+        self.watchers
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix.as_str()))
+    }
+
+    /// Send an event to the watchers of its key, the event is only built if
+    /// someone watches the key
+    pub fn notify(&mut self, key: &str, event: impl FnOnce() -> WatchEvent) {
+        if !self.watches(key) {
+            return;
+        }
+        let event = event();
+        self.watchers.retain(|(prefix, sender)| {
+            !key.starts_with(prefix.as_str()) || sender.send(event.clone()).is_ok()
+        });
+    }
+}
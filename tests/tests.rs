@@ -1,8 +1,20 @@
+// 原有的cli测试用`.args(&[..])`传参数，保持原样
+#![allow(clippy::needless_borrows_for_generic_args)]
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    Commands, Compression, EncryptionKey, FamilyOptions, IndexMode, KvErr, KvStore, KvsClient,
+    KvsEngine, KvsServer, LsmStore, MemStore, MergeOperator, Options, Reply, Result, WatchEvent,
+    WriteBatch,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -179,8 +191,17 @@ fn cli_invalid_subcommand() {
 // Should get previously stored value.
 #[test]
 fn get_stored_value() -> Result<()> {
+    check_get_stored_value(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_get_stored_value() -> Result<()> {
+    check_get_stored_value(|path| LsmStore::open(path))
+}
+
+fn check_get_stored_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -190,7 +211,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -200,8 +221,17 @@ fn get_stored_value() -> Result<()> {
 // Should overwrite existent value.
 #[test]
 fn overwrite_value() -> Result<()> {
+    check_overwrite_value(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_overwrite_value() -> Result<()> {
+    check_overwrite_value(|path| LsmStore::open(path))
+}
+
+fn check_overwrite_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -210,7 +240,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -221,15 +251,24 @@ fn overwrite_value() -> Result<()> {
 // Should get `None` when getting a non-existent key.
 #[test]
 fn get_non_existent_value() -> Result<()> {
+    check_get_non_existent_value(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_get_non_existent_value() -> Result<()> {
+    check_get_non_existent_value(|path| LsmStore::open(path))
+}
+
+fn check_get_non_existent_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -237,16 +276,44 @@ fn get_non_existent_value() -> Result<()> {
 
 #[test]
 fn remove_non_existent_key() -> Result<()> {
+    check_remove_non_existent_key(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_remove_non_existent_key() -> Result<()> {
+    check_remove_non_existent_key(|path| LsmStore::open(path))
+}
+
+#[test]
+fn mem_remove_non_existent_key() -> Result<()> {
+    check_remove_non_existent_key(|_| Ok(MemStore::new()))
+}
+
+fn check_remove_non_existent_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
 #[test]
 fn remove_key() -> Result<()> {
+    check_remove_key(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_remove_key() -> Result<()> {
+    check_remove_key(|path| LsmStore::open(path))
+}
+
+#[test]
+fn mem_remove_key() -> Result<()> {
+    check_remove_key(|_| Ok(MemStore::new()))
+}
+
+fn check_remove_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -257,8 +324,17 @@ fn remove_key() -> Result<()> {
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
+    check_compaction(|path| KvStore::open(path))
+}
+
+#[test]
+fn lsm_compaction() -> Result<()> {
+    check_compaction(|path| LsmStore::open(path))
+}
+
+fn check_compaction<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let mut store = open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -288,7 +364,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let mut store = open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             // println!("key: {}", key);
@@ -299,3 +375,1858 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Start a server on an ephemeral port, serving a store in the given directory.
+fn start_server(temp_dir: &TempDir) -> Result<String> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    thread::spawn(move || KvsServer::new(store).serve(listener));
+    Ok(addr)
+}
+
+// Batched multi_get/multi_set should behave like the single-key calls.
+#[test]
+fn client_multi_get_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    let pairs = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.multi_set(pairs)?;
+    client.remove("key7".to_owned())?;
+    assert!(client.remove("key7".to_owned()).is_err());
+
+    let keys = (0..101).map(|i| format!("key{}", i)).collect();
+    let values = client.multi_get(keys)?;
+    for (i, value) in values.into_iter().enumerate() {
+        match i {
+            7 | 100 => assert_eq!(value, None),
+            _ => assert_eq!(value, Some(format!("value{}", i))),
+        }
+    }
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Batches larger than the TCP buffers in both directions complete.
+#[test]
+fn client_large_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = |i: usize| format!("{:04096}", i);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_many((0..5000).map(|i| (key(i), "v".repeat(4096))).collect())?;
+    drop(store);
+    let addr = start_server(&temp_dir)?;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let result = KvsClient::connect(addr)
+            .and_then(|mut client| client.multi_get((0..5000).map(key).collect()));
+        sender.send(result).unwrap();
+    });
+    let values = receiver
+        .recv_timeout(Duration::from_secs(60))
+        .expect("large batch did not complete")?;
+    assert_eq!(values.len(), 5000);
+    assert!(values.iter().all(|value| *value == Some("v".repeat(4096))));
+    Ok(())
+}
+
+// Responses of pipelined requests can be received in any order.
+#[test]
+fn client_pipelined_out_of_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut client = KvsClient::connect(addr)?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let first = client.send(Commands::Get { key: "key1".to_owned() })?;
+    let second = client.send(Commands::Get { key: "key2".to_owned() })?;
+    client.flush()?;
+    assert!(matches!(client.recv(second)?, Reply::Value(None)));
+    match client.recv(first)? {
+        Reply::Value(value) => assert_eq!(value, Some("value1".to_owned())),
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    Ok(())
+}
+
+// A second client is served while the first one is still connected.
+#[test]
+fn client_concurrent_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+    let mut first = KvsClient::connect(&addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let result = KvsClient::connect(addr).and_then(|mut second| {
+            second.set("key2".to_owned(), "value2".to_owned())?;
+            second.get("key1".to_owned())
+        });
+        sender.send(result).unwrap();
+    });
+    let value = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("second client was not served")?;
+    assert_eq!(value, Some("value1".to_owned()));
+    assert_eq!(first.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `get_many` should return values in key order, across data files and after reopen.
+#[test]
+fn get_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let pairs = (0..500)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    store.set_many(pairs)?;
+    store.set("key3".to_owned(), "new3".to_owned())?;
+    store.remove("key4".to_owned())?;
+
+    let keys: Vec<String> = (0..501).rev().map(|i| format!("key{}", i)).collect();
+    let check = |values: Vec<Option<String>>| {
+        for (key, value) in keys.iter().zip(values) {
+            let expected = match key.as_str() {
+                "key3" => Some("new3".to_owned()),
+                "key4" | "key500" => None,
+                _ => Some(key.replace("key", "value")),
+            };
+            assert_eq!(value, expected);
+        }
+    };
+    check(store.get_many(&keys)?);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(store.get_many(&keys)?);
+    Ok(())
+}
+
+#[test]
+fn cli_get_set_many() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set-many", "key1", "value1", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set-many", "key1", "value1", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get-many", "key2", "key3", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2\nKey not found\nvalue1\n"));
+}
+
+// A read-only store sees the writer's records after `refresh` and rejects writes.
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvErr::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvErr::ReadOnly)
+    ));
+
+    writer.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    reader.refresh()?;
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Overwrite enough data for the writer to compact and delete old files.
+    for iter in 0..100 {
+        writer.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    reader.refresh()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("99".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+    assert!(!temp_dir.path().join("missing").exists());
+    Ok(())
+}
+
+// Only one writable store can be open on a directory at a time.
+#[test]
+fn exclusive_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvErr::Locked)));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // readers do not take the lock
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `open` trusts the manifest: data files it does not list are not read, and are deleted.
+#[test]
+fn manifest_removes_stray_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    fs::write(
+        temp_dir.path().join("store_file_99.txt"),
+        r#"{"Set":{"key":"key2","value":"value2"}}{"Set":{"key":"#,
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(!temp_dir.path().join("store_file_99.txt").exists());
+    Ok(())
+}
+
+// A directory written before manifests existed is still opened from its data files.
+#[test]
+fn manifest_created_for_legacy_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Read every file of a store directory into memory.
+fn snapshot_dir(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| {
+            let data = fs::read(&path).unwrap();
+            (path, data)
+        })
+        .collect()
+}
+
+// Replace the content of a store directory with the given files.
+fn restore_dir(dir: &Path, files: &[(PathBuf, Vec<u8>)]) {
+    for entry in fs::read_dir(dir).unwrap() {
+        fs::remove_file(entry.unwrap().path()).unwrap();
+    }
+    for (path, data) in files {
+        fs::write(path, data).unwrap();
+    }
+}
+
+// Live data file ids listed in the manifest of a store directory.
+fn manifest_files(dir: &Path) -> Vec<u64> {
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(dir.join("MANIFEST")).unwrap()).unwrap();
+    manifest["files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|id| id.as_u64().unwrap())
+        .collect()
+}
+
+// Rebuild the directory a crash would leave at each step of a compaction and
+// check the store opens to either the state before or after it.
+#[test]
+fn compaction_crash_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let mut store = KvStore::open(dir)?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    drop(store);
+    let before = snapshot_dir(dir);
+
+    let mut store = KvStore::open(dir)?;
+    let mut iter = 0;
+    while dir.join("store_file_0.txt").exists() {
+        iter += 1;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    drop(store);
+    let after = snapshot_dir(dir);
+    let merged_file_id = manifest_files(dir)[0];
+    let merged_path = dir.join(format!("store_file_{}.txt", merged_file_id));
+    let merged = fs::read(&merged_path)?;
+
+    let check = |expected: &str| -> Result<()> {
+        let mut store = KvStore::open(dir)?;
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(expected.to_owned())
+            );
+        }
+        Ok(())
+    };
+
+    // crash while writing the merge output
+    restore_dir(dir, &before);
+    let tmp_path = dir.join(format!("store_file_{}.tmp", merged_file_id));
+    fs::write(&tmp_path, &merged[..merged.len() / 2])?;
+    check("old")?;
+    assert!(!tmp_path.exists());
+
+    // crash after renaming the merge output, before the manifest is updated
+    restore_dir(dir, &before);
+    fs::write(&merged_path, &merged)?;
+    check("old")?;
+    assert!(!merged_path.exists());
+
+    // crash after the manifest is updated, before old files are deleted
+    let mut files = after.clone();
+    files.extend(before.into_iter().filter(|(path, _)| !path.ends_with("MANIFEST")));
+    restore_dir(dir, &files);
+    check(&format!("{}", iter))?;
+    assert!(!dir.join("store_file_0.txt").exists());
+    Ok(())
+}
+
+// `remove` appends its tombstone to the active file, not to the file holding the key.
+#[test]
+fn tombstone_in_active_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let mut store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    while dir.join("store_file_0.txt").exists() {
+        store.set("key2".to_owned(), "value2".to_owned())?;
+    }
+    let mut filler = 0;
+    while manifest_files(dir).len() < 2 {
+        filler += 1;
+        store.set(format!("filler{}", filler), "x".repeat(1024))?;
+    }
+    let files = manifest_files(dir);
+    let merged_path = dir.join(format!("store_file_{}.txt", files[0]));
+    let merged_len = fs::metadata(&merged_path)?.len();
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(fs::metadata(&merged_path)?.len(), merged_len);
+    drop(store);
+
+    let mut store = KvStore::open(dir)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A key removed before a compaction must not come back when files of the
+// previous generation are still around after a crash.
+#[test]
+fn tombstone_reopen_after_partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let mut store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let before = snapshot_dir(dir);
+
+    let mut store = KvStore::open(dir)?;
+    store.remove("key1".to_owned())?;
+    while dir.join("store_file_0.txt").exists() {
+        store.set("key2".to_owned(), "value2".to_owned())?;
+    }
+    drop(store);
+
+    let mut files = snapshot_dir(dir);
+    files.extend(before.into_iter().filter(|(path, _)| !path.ends_with("MANIFEST")));
+    restore_dir(dir, &files);
+    for _ in 0..2 {
+        let mut store = KvStore::open(dir)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert!(store.remove("key1".to_owned()).is_err());
+    }
+    Ok(())
+}
+
+// Compaction only rewrites files that are mostly garbage, a file of cold data is kept as is.
+#[test]
+fn compaction_skips_cold_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let mut store = KvStore::open(dir)?;
+    let cold = "c".repeat(600 * 1024);
+    store.set("cold1".to_owned(), cold.clone())?;
+    store.set("cold2".to_owned(), cold.clone())?;
+    let cold_file = manifest_files(dir)[0];
+    assert!(manifest_files(dir).len() > 1, "active file not rotated");
+
+    while store.stats().compaction.compactions == 0 {
+        store.set("hot".to_owned(), "value".to_owned())?;
+    }
+    for iter in 0..1000 {
+        store.set("hot".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(manifest_files(dir)[0], cold_file);
+
+    drop(store);
+    let mut store = KvStore::open(dir)?;
+    assert_eq!(store.get("cold1".to_owned())?, Some(cold.clone()));
+    assert_eq!(store.get("cold2".to_owned())?, Some(cold));
+    assert_eq!(store.get("hot".to_owned())?, Some("999".to_owned()));
+    Ok(())
+}
+
+// Merging the active file does not leave a small data file behind every time,
+// small files are merged again until they fill up.
+#[test]
+fn compaction_bounded_file_count() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let mut store = KvStore::open(dir)?;
+    for key_id in 0..20000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        for iter in 0..5 {
+            store.set("hot".to_owned(), format!("{}", iter))?;
+        }
+    }
+    assert!(store.stats().compaction.compactions > 10);
+    let files = manifest_files(dir);
+    assert!(files.len() <= 4, "{} data files", files.len());
+
+    drop(store);
+    let mut store = KvStore::open(dir)?;
+    assert_eq!(store.stats().live_keys, 20001);
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("hot".to_owned())?, Some("4".to_owned()));
+    Ok(())
+}
+
+// Data files, including merge outputs, stay close to the maximum file size.
+#[test]
+fn compaction_bounded_output_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let mut store = KvStore::open(dir)?;
+    for iter in 0..5 {
+        for key_id in 0..30 {
+            let value = format!("{}", iter).repeat(100 * 1024);
+            store.set(format!("key{}", key_id), value)?;
+        }
+    }
+    for data in manifest_files(dir) {
+        let len = fs::metadata(dir.join(format!("store_file_{}.txt", data)))?.len();
+        assert!(len <= 1024 * 1024 + 101 * 1024, "file {} has {} bytes", data, len);
+    }
+
+    drop(store);
+    let mut store = KvStore::open(dir)?;
+    for key_id in 0..30 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("4".repeat(100 * 1024))
+        );
+    }
+    Ok(())
+}
+
+// `stats` reports live and dead data, and compaction history survives a reopen.
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let mut store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 2);
+    assert!(stats.dead_bytes > 0);
+    assert_eq!(
+        stats.files.iter().map(|file| file.dead_bytes).sum::<u64>(),
+        stats.dead_bytes
+    );
+    assert_eq!(stats.compaction.compactions, 0);
+
+    while store.stats().compaction.compactions == 0 {
+        store.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    let stats = store.stats();
+    assert!(stats.compaction.last_reclaimed_bytes > 0);
+    assert_eq!(
+        stats.compaction.total_reclaimed_bytes,
+        stats.compaction.last_reclaimed_bytes
+    );
+    drop(store);
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.stats().compaction.compactions, 1);
+    assert_eq!(store.stats().live_keys, 2);
+    Ok(())
+}
+
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    // the store is still open for writing
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live keys: 1"));
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--json"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats: kvs::Stats = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(stats.live_keys, 1);
+    drop(store);
+    Ok(())
+}
+
+// Paused compaction lets garbage grow until `compact_now` or `resume_compaction`.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let mut store = KvStore::open(dir)?;
+    store.pause_compaction();
+    for iter in 0..1000 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.stats().compaction.compactions, 0);
+    assert!(store.stats().dead_bytes > 1024);
+
+    store.compact_now()?;
+    let stats = store.stats();
+    assert_eq!(stats.compaction.compactions, 1);
+    assert_eq!(stats.dead_bytes, 0);
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("999".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    for iter in 0..1000 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.stats().compaction.compactions, 1);
+    store.resume_compaction();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats().compaction.compactions, 2);
+    Ok(())
+}
+
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().dead_bytes, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Reads through memory maps see the same data as buffered reads, before and after compaction.
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        mmap_reads: true,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value\"{}\"", key_id))?;
+    }
+    store.compact_now()?;
+    store.set("key1".to_owned(), "active".to_owned())?;
+    for _ in 0..2 {
+        assert_eq!(store.get("key0".to_owned())?, Some("value\"0\"".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("active".to_owned()));
+        assert_eq!(store.get("key100".to_owned())?, None);
+    }
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    store.compact_now()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new0".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("new{}", key_id)));
+    }
+    Ok(())
+}
+
+// Records written with different compressions, and legacy records, coexist in one store.
+#[test]
+fn value_compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    fs::write(
+        dir.join("store_file_0.txt"),
+        r#"{"Set":{"key":"legacy","value":"value"}}"#,
+    )?;
+    let document = |key_id: usize| {
+        format!(r#"{{"id": {}, "name": "{}", "tags": ["a", "b", "c"]}}"#, key_id, "x".repeat(200))
+    };
+
+    for (round, compression) in [Compression::Lz4, Compression::Zstd, Compression::None]
+        .into_iter()
+        .enumerate()
+    {
+        let options = Options {
+            compression,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with(dir, options)?;
+        store.pause_compaction();
+        let before = store.stats().live_bytes;
+        for key_id in 0..100 {
+            store.set(format!("key{}_{}", round, key_id), document(key_id))?;
+        }
+        let written = store.stats().live_bytes - before;
+        if compression == Compression::None {
+            assert!(written > 100 * document(0).len() as u64);
+        } else {
+            assert!(written < 100 * document(0).len() as u64 / 2);
+        }
+    }
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get("legacy".to_owned())?, Some("value".to_owned()));
+        for round in 0..3 {
+            for key_id in 0..100 {
+                assert_eq!(
+                    store.get(format!("key{}_{}", round, key_id))?,
+                    Some(document(key_id))
+                );
+            }
+        }
+        Ok(())
+    };
+    let mut store = KvStore::open(dir)?;
+    check(&mut store)?;
+    store.compact_now()?;
+    check(&mut store)?;
+    drop(store);
+
+    let options = Options {
+        mmap_reads: true,
+        ..Options::default()
+    };
+    check(&mut KvStore::open_with(dir, options)?)?;
+    Ok(())
+}
+
+// Values written with an encryption key are unreadable on disk and only
+// accessible with the same key
+#[test]
+fn encryption_at_rest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let options = |key: u8| Options {
+        encryption_key: Some(EncryptionKey([key; 32])),
+        compression: Compression::Lz4,
+        mmap_reads: true,
+        ..Options::default()
+    };
+
+    let mut store = KvStore::open_with(dir, options(1))?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("secret{}", key_id))?;
+    }
+    store.set("doc".to_owned(), "plaintext".repeat(50))?;
+    for key_id in 0..50 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.compact_now()?;
+    drop(store);
+
+    for entry in fs::read_dir(dir)? {
+        let content = fs::read(entry?.path())?;
+        let content = String::from_utf8_lossy(&content);
+        assert!(!content.contains("secret"));
+        assert!(!content.contains("plaintext"));
+    }
+
+    let mut store = KvStore::open_with(dir, options(1))?;
+    for key_id in 50..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("secret{}", key_id))
+        );
+    }
+    assert_eq!(store.get("doc".to_owned())?, Some("plaintext".repeat(50)));
+    drop(store);
+
+    match KvStore::open_with(dir, options(2)) {
+        Err(KvErr::Decryption) => {}
+        other => panic!("expected Decryption, got {:?}", other.map(|_| ())),
+    }
+    match KvStore::open(dir) {
+        Err(KvErr::Decryption) => {}
+        other => panic!("expected Decryption, got {:?}", other.map(|_| ())),
+    }
+
+    // flipping the last byte of a record makes its authentication fail
+    let files = manifest_files(dir);
+    let path = dir.join(format!("store_file_{}.txt", files[0]));
+    let mut content = fs::read(&path)?;
+    let last = content.len() - 1;
+    content[last] ^= 1;
+    fs::write(&path, content)?;
+    let mut store = KvStore::open_with(dir, options(1))?;
+    let mut tampered = 0;
+    for key_id in 50..100 {
+        match store.get(format!("key{}", key_id)) {
+            Ok(value) => assert_eq!(value, Some(format!("secret{}", key_id))),
+            Err(KvErr::Decryption) => tampered += 1,
+            Err(err) => return Err(err),
+        }
+    }
+    match store.get("doc".to_owned()) {
+        Ok(value) => assert_eq!(value, Some("plaintext".repeat(50))),
+        Err(KvErr::Decryption) => tampered += 1,
+        Err(err) => return Err(err),
+    }
+    assert_eq!(tampered, 1);
+    Ok(())
+}
+
+// Record headers are authenticated with the payload, and an encrypted store
+// refuses records written without encryption
+#[test]
+fn encryption_rejects_unauthenticated_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let options = Options {
+        encryption_key: Some(EncryptionKey([1; 32])),
+        ..Options::default()
+    };
+
+    let mut store = KvStore::open_with(dir, options.clone())?;
+    store.set("key1".to_owned(), "secret1".to_owned())?;
+    store.set("key2".to_owned(), "secret2".to_owned())?;
+    drop(store);
+
+    // clear the encrypted flag of key1 and inject a plaintext record for key3
+    let files = manifest_files(dir);
+    let path = dir.join(format!("store_file_{}.txt", files[files.len() - 1]));
+    let header = r#"{"Put":{"key":"key1","flags":4,"#;
+    let mut content = fs::read(&path)?;
+    let pos = content
+        .windows(header.len())
+        .position(|window| window == header.as_bytes())
+        .unwrap();
+    content[pos + header.len() - 2] = b'0';
+    content.extend_from_slice(br#"{"Put":{"key":"key3","flags":0,"len":8}}injected"#);
+    fs::write(&path, content)?;
+
+    let mut store = KvStore::open_with(dir, options.clone())?;
+    match store.get("key1".to_owned()) {
+        Err(KvErr::Decryption) => {}
+        other => panic!("expected Decryption, got {:?}", other),
+    }
+    match store.get("key3".to_owned()) {
+        Err(KvErr::Decryption) => {}
+        other => panic!("expected Decryption, got {:?}", other),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("secret2".to_owned()));
+    store.set("key4".to_owned(), "secret4".to_owned())?;
+    store.remove("key4".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with(dir, options.clone())?;
+    assert_eq!(store.get("key4".to_owned())?, None);
+    drop(store);
+
+    // the sequence number in the header of key2 is authenticated too
+    let header = r#"{"Put":{"key":"key2","flags":4,"#;
+    let mut content = fs::read(&path)?;
+    let pos = content
+        .windows(header.len())
+        .position(|window| window == header.as_bytes())
+        .unwrap();
+    let seq = content[pos..]
+        .windows(6)
+        .position(|window| window == br#""seq":"#)
+        .unwrap();
+    content[pos + seq + 6] ^= 1;
+    fs::write(&path, &content)?;
+    let mut store = KvStore::open_with(dir, options.clone())?;
+    match store.get("key2".to_owned()) {
+        Err(KvErr::Decryption) => {}
+        other => panic!("expected Decryption, got {:?}", other),
+    }
+    drop(store);
+
+    // a tombstone without an authentication tag is refused on open
+    content.extend_from_slice(br#"{"Rm":{"key":"key2","seq":100}}"#);
+    fs::write(&path, content)?;
+    assert!(matches!(
+        KvStore::open_with(dir, options),
+        Err(KvErr::Decryption)
+    ));
+
+    // an existing unencrypted store cannot be opened with a key
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let options = Options {
+        encryption_key: Some(EncryptionKey([1; 32])),
+        ..Options::default()
+    };
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), options),
+        Err(KvErr::InvalidOptions(_))
+    ));
+    Ok(())
+}
+
+// Large values can be written from a reader and read back through a reader
+#[test]
+fn streaming_values() -> Result<()> {
+    use std::io::{Cursor, Read};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let blob: Vec<u8> = (0..3 * 1024 * 1024 + 17).map(|i| (i % 251) as u8).collect();
+    let read_value = |store: &KvStore, key: &str| -> Result<Option<Vec<u8>>> {
+        match store.get_reader(key.to_owned())? {
+            Some(mut reader) => {
+                let mut value = Vec::new();
+                reader.read_to_end(&mut value)?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    };
+
+    for key in [None, Some(EncryptionKey([7; 32]))] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            encryption_key: key,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set_reader("blob".to_owned(), Cursor::new(&blob), blob.len() as u64)?;
+        store.set_reader("empty".to_owned(), Cursor::new(Vec::new()), 0)?;
+        store.set_reader("text".to_owned(), &b"streamed"[..], 8)?;
+        assert_eq!(read_value(&store, "blob")?, Some(blob.clone()));
+        assert_eq!(read_value(&store, "empty")?, Some(Vec::new()));
+        assert_eq!(store.get("text".to_owned())?, Some("streamed".to_owned()));
+        // ranges skip the bytes before them, also across the chunks of an encrypted value
+        let chunk = 64 * 1024;
+        for range in [
+            0..10,
+            chunk - 3..chunk + 5,
+            3 * chunk..5 * chunk + 1,
+            blob.len() - 4..blob.len() + 4,
+            blob.len() + 1..blob.len() + 9,
+        ] {
+            let expected = blob[range.start.min(blob.len())..range.end.min(blob.len())].to_vec();
+            let range = range.start as u64..range.end as u64;
+            assert_eq!(store.get_range("blob".to_owned(), range)?, Some(expected));
+        }
+        assert_eq!(
+            store.get_range("text".to_owned(), 3..6)?,
+            Some(b"eam".to_vec())
+        );
+        drop(store);
+
+        let mut store = KvStore::open_with(temp_dir.path(), options)?;
+        store.compact_now()?;
+        assert_eq!(read_value(&store, "blob")?, Some(blob.clone()));
+        assert_eq!(read_value(&store, "text")?, Some(b"streamed".to_vec()));
+    }
+
+    // a short reader writes nothing and leaves the store usable
+    let mut store = KvStore::open(dir)?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert!(store
+        .set_reader("short".to_owned(), &blob[..100], 200)
+        .is_err());
+    assert_eq!(read_value(&store, "short")?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(dir)?;
+    assert_eq!(read_value(&store, "key")?, Some(b"value".to_vec()));
+    assert_eq!(read_value(&store, "key2")?, Some(b"value2".to_vec()));
+    assert_eq!(read_value(&store, "short")?, None);
+    Ok(())
+}
+
+// The disk index returns the same values as the in-memory index across
+// checkpoints, compaction, read-only handles and switching index modes
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let options = Options {
+        index_mode: IndexMode::Disk { cache_pages: 4 },
+        ..Options::default()
+    };
+    let index_files = || -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".idx"))
+            .collect()
+    };
+    let check = |store: &mut KvStore| -> Result<()> {
+        for key_id in 0..10000 {
+            let expected = if key_id % 5 == 0 {
+                None
+            } else if key_id % 3 == 0 {
+                Some(format!("new{}", key_id))
+            } else {
+                Some(format!("value{}", key_id))
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        assert_eq!(store.stats().live_keys, 8000);
+        Ok(())
+    };
+
+    let mut store = KvStore::open_with(dir, options.clone())?;
+    for key_id in 0..10000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..10000).step_by(3) {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    for key_id in (0..10000).step_by(5) {
+        store.remove(format!("key{}", key_id))?;
+    }
+    check(&mut store)?;
+    assert_eq!(index_files().len(), 1);
+    drop(store);
+
+    let mut store = KvStore::open_with(dir, options.clone())?;
+    check(&mut store)?;
+    store.compact_now()?;
+    check(&mut store)?;
+    let live_bytes = store.stats().live_bytes;
+    let mut read_only = KvStore::open_with(
+        dir,
+        Options {
+            read_only: true,
+            ..options.clone()
+        },
+    )?;
+    check(&mut read_only)?;
+    drop(read_only);
+    drop(store);
+
+    let mut store = KvStore::open(dir)?;
+    check(&mut store)?;
+    assert_eq!(store.stats().live_bytes, live_bytes);
+    assert!(index_files().is_empty());
+    drop(store);
+
+    let mut store = KvStore::open_with(dir, options)?;
+    check(&mut store)?;
+    assert_eq!(store.stats().live_bytes, live_bytes);
+    Ok(())
+}
+
+// LsmStore keeps keys sorted across the memtable and every level of SSTables.
+#[test]
+fn lsm_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let expected = |key_id: usize| {
+        if key_id.is_multiple_of(7) {
+            None
+        } else {
+            Some(format!("value{}-{:0100}", key_id, 2))
+        }
+    };
+
+    let mut store = LsmStore::open(dir)?;
+    for round in 0..3 {
+        for key_id in 0..20000 {
+            store.set(format!("key{:05}", key_id), format!("value{}-{:0100}", key_id, round))?;
+        }
+    }
+    for key_id in (0..20000).step_by(7) {
+        store.remove(format!("key{:05}", key_id))?;
+    }
+    let table_count = fs::read_dir(dir)?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+        .count();
+    assert!(table_count > 1);
+
+    let check = |store: &mut LsmStore| -> Result<()> {
+        let pairs = store.scan("key00100".to_owned().."key00200".to_owned())?;
+        let want: Vec<_> = (100..200)
+            .filter_map(|key_id| expected(key_id).map(|value| (format!("key{:05}", key_id), value)))
+            .collect();
+        assert_eq!(pairs, want);
+        assert_eq!(store.scan(..)?.len(), 20000 - 20000_usize.div_ceil(7));
+        for key_id in (0..20000).step_by(997) {
+            assert_eq!(store.get(format!("key{:05}", key_id))?, expected(key_id));
+        }
+        assert!(store.remove("key00007".to_owned()).is_err());
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+
+    let mut store = LsmStore::open(dir)?;
+    check(&mut store)?;
+    drop(store);
+    assert!(KvStore::open(dir).is_err());
+
+    // values of an LsmStore are never compressed nor encrypted
+    for options in [
+        Options {
+            compression: Compression::Zstd,
+            ..Options::default()
+        },
+        Options {
+            encryption_key: Some(EncryptionKey([1; 32])),
+            ..Options::default()
+        },
+    ] {
+        assert!(matches!(
+            LsmStore::open_with(dir, options),
+            Err(KvErr::InvalidOptions(_))
+        ));
+    }
+    Ok(())
+}
+
+// Lookups of missing keys should be answered by the Bloom filters without reading pages.
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    let files_with = |extension: &str| {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some(extension.as_ref()))
+            .count()
+    };
+
+    let options = Options {
+        bloom_fp_rate: Some(0.01),
+        ..Options::default()
+    };
+    let mut store = LsmStore::open_with(dir, options.clone())?;
+    for key_id in 0..30000 {
+        store.set(format!("key{}", key_id), format!("value{}-{:0100}", key_id, 0))?;
+    }
+    assert!(files_with("sst") > 0);
+    assert_eq!(files_with("bloom"), files_with("sst"));
+    for key_id in 0..30000 {
+        assert_eq!(store.get(format!("missing{}", key_id))?, None);
+    }
+    let bloom = store.bloom_stats();
+    assert!(bloom.checks >= 30000);
+    assert!(bloom.false_positives < bloom.checks / 20);
+    assert_eq!(bloom.skipped_reads + bloom.false_positives, bloom.checks);
+    assert_eq!(store.get("key1234".to_owned())?, Some(format!("value1234-{:0100}", 0)));
+    drop(store);
+
+    let options = Options {
+        index_mode: IndexMode::Disk { cache_pages: 4 },
+        ..options
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..10000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let before = store.stats().bloom;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("missing{}", key_id))?, None);
+    }
+    let bloom = store.stats().bloom;
+    assert_eq!(bloom.checks - before.checks, 1000);
+    assert!(bloom.skipped_reads - before.skipped_reads > 950);
+    drop(store);
+
+    // tables written before Bloom filters have none, and can still be replaced
+    let remove_filters = |dir: &Path| {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension() == Some("bloom".as_ref()) {
+                fs::remove_file(path).unwrap();
+            }
+        }
+    };
+    remove_filters(temp_dir.path());
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..10000 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    assert_eq!(store.get("key1234".to_owned())?, Some("new1234".to_owned()));
+    remove_filters(dir);
+    let mut store = LsmStore::open(dir)?;
+    for key_id in 0..30000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert_eq!(
+        store.get("key1234".to_owned())?,
+        Some("value1234".to_owned())
+    );
+
+    let invalid = Options {
+        bloom_fp_rate: Some(1.5),
+        ..Options::default()
+    };
+    assert!(matches!(
+        LsmStore::open_with(dir, invalid),
+        Err(KvErr::InvalidOptions(_))
+    ));
+    Ok(())
+}
+
+// MemStore evicts the least recently used keys once its size cap is reached.
+#[test]
+fn mem_store_size_cap() -> Result<()> {
+    let mut store = MemStore::with_capacity(100);
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    // every pair takes 9 bytes, so all of them fit
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    store.set("key10".to_owned(), "value".to_owned())?;
+    store.set("key11".to_owned(), "value".to_owned())?;
+    // key1 and key2 are the least recently used, key0 was just read
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key11".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvErr::KeyNotFound)
+    ));
+
+    // a pair larger than the cap is not stored
+    store.set("big".to_owned(), "x".repeat(200))?;
+    assert_eq!(store.get("big".to_owned())?, None);
+    Ok(())
+}
+
+// Cached values are served without reading the data files and dropped on writes.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        value_cache_bytes: 64,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let cache = store.stats().cache;
+    assert_eq!((cache.hits, cache.misses, cache.bytes), (1, 1, 10));
+    assert_eq!(cache.capacity_bytes, 64);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set_many(vec![("key1".to_owned(), "value3".to_owned())])?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // the budget bounds the cached bytes
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        store.get(format!("key{}", key_id))?;
+    }
+    assert!(store.stats().cache.bytes <= 64);
+
+    // a read-only handle drops its cache when it picks up new writes
+    let mut reader = KvStore::open_with(
+        temp_dir.path(),
+        Options {
+            read_only: true,
+            ..options
+        },
+    )?;
+    assert_eq!(reader.get("key5".to_owned())?, Some("value5".to_owned()));
+    store.set("key5".to_owned(), "new5".to_owned())?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key5".to_owned())?, Some("new5".to_owned()));
+    Ok(())
+}
+
+// Values past their TTL count as garbage, and compaction reclaims them even when
+// an older data file is kept.
+#[test]
+fn expired_values_compaction() -> Result<()> {
+    let index_modes = [IndexMode::Memory, IndexMode::Disk { cache_pages: 4 }];
+    for index_mode in index_modes {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dir = temp_dir.path();
+        let options = Options {
+            index_mode,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with(dir, options.clone())?;
+        store.set("cold".to_owned(), "c".repeat(1100 * 1024))?;
+        assert_eq!(manifest_files(dir).len(), 2);
+        store.create_family(
+            "sessions",
+            FamilyOptions {
+                ttl: Some(Duration::from_millis(500)),
+                ..FamilyOptions::default()
+            },
+        )?;
+        for key_id in 0..1000 {
+            store.set_cf("sessions", format!("key{}", key_id), "s".repeat(100))?;
+        }
+        let stats = store.stats();
+        assert_eq!(stats.live_keys, 1001);
+        assert_eq!(stats.compaction.compactions, 0);
+
+        // expiry is tracked by the second
+        thread::sleep(Duration::from_millis(1600));
+        let stats = store.stats();
+        assert_eq!(stats.live_keys, 1);
+        assert!(stats.dead_bytes > 100 * 1000);
+        assert!(stats.live_bytes < 1200 * 1024);
+
+        store.set("key".to_owned(), "value".to_owned())?;
+        let stats = store.stats();
+        assert_eq!(stats.compaction.compactions, 1);
+        assert_eq!(stats.dead_bytes, 0);
+        assert_eq!(stats.live_keys, 2);
+        drop(store);
+
+        let mut store = KvStore::open_with(dir, options)?;
+        assert_eq!(store.stats().live_keys, 2);
+        assert_eq!(store.get_cf("sessions", "key0".to_owned())?, None);
+        assert_eq!(store.get("cold".to_owned())?, Some("c".repeat(1100 * 1024)));
+        store.compact_now()?;
+        assert_eq!(store.stats().live_keys, 2);
+        assert!(store.stats().live_bytes < 1200 * 1024);
+    }
+    Ok(())
+}
+
+// Column families have independent key spaces and options, and can be dropped.
+#[test]
+fn column_families() -> Result<()> {
+    let index_modes = [IndexMode::Memory, IndexMode::Disk { cache_pages: 4 }];
+    for index_mode in index_modes {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dir = temp_dir.path();
+        let options = Options {
+            index_mode,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with(dir, options.clone())?;
+        store.create_family("users", FamilyOptions::default())?;
+        store.create_family(
+            "logs",
+            FamilyOptions {
+                compression: Compression::Zstd,
+                ..FamilyOptions::default()
+            },
+        )?;
+        store.create_family(
+            "sessions",
+            FamilyOptions {
+                ttl: Some(Duration::from_millis(200)),
+                ..FamilyOptions::default()
+            },
+        )?;
+        assert!(matches!(
+            store.create_family("users", FamilyOptions::default()),
+            Err(KvErr::FamilyExists(_))
+        ));
+        assert!(matches!(
+            store.get_cf("unknown", "key".to_owned()),
+            Err(KvErr::UnknownFamily(_))
+        ));
+
+        store.set("key".to_owned(), "default".to_owned())?;
+        store.set_cf("users", "key".to_owned(), "user".to_owned())?;
+        store.set_cf("logs", "key".to_owned(), "x".repeat(1000))?;
+        store.set_cf("sessions", "key".to_owned(), "session".to_owned())?;
+        assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+        assert_eq!(store.get_cf("users", "key".to_owned())?, Some("user".to_owned()));
+        assert_eq!(store.get_cf("logs", "key".to_owned())?, Some("x".repeat(1000)));
+        assert!(matches!(
+            store.remove_cf("users", "missing".to_owned()),
+            Err(KvErr::KeyNotFound)
+        ));
+        store.remove_cf("users", "key".to_owned())?;
+        assert_eq!(store.get_cf("users", "key".to_owned())?, None);
+        assert_eq!(store.get("key".to_owned())?, Some("default".to_owned()));
+
+        let mut batch = WriteBatch::new();
+        batch
+            .set("batch".to_owned(), "default".to_owned())
+            .set_cf("users", "batch".to_owned(), "user".to_owned())
+            .remove("key".to_owned())
+            .remove_cf("users", "missing".to_owned());
+        store.write_batch(batch)?;
+        let mut batch = WriteBatch::new();
+        batch
+            .set("never".to_owned(), "written".to_owned())
+            .set_cf("unknown", "key".to_owned(), "value".to_owned());
+        assert!(store.write_batch(batch).is_err());
+
+        // keys of the other families can not be reached from the default family
+        for id in 0..3 {
+            let key = format!("\0{}\0batch", id);
+            assert!(matches!(store.get(key.clone()), Err(KvErr::ReservedKey(_))));
+            assert!(matches!(
+                store.set(key.clone(), "value".to_owned()),
+                Err(KvErr::ReservedKey(_))
+            ));
+            assert!(matches!(
+                store.remove(key.clone()),
+                Err(KvErr::ReservedKey(_))
+            ));
+            let mut batch = WriteBatch::new();
+            batch.set(key, "value".to_owned());
+            assert!(matches!(
+                store.write_batch(batch),
+                Err(KvErr::ReservedKey(_))
+            ));
+        }
+        drop(store);
+
+        let mut store = KvStore::open_with(dir, options.clone())?;
+        assert_eq!(store.families(), vec!["logs", "sessions", "users"]);
+        assert_eq!(store.get("key".to_owned())?, None);
+        assert_eq!(store.get("never".to_owned())?, None);
+        assert_eq!(store.get("batch".to_owned())?, Some("default".to_owned()));
+        assert_eq!(store.get_cf("users", "batch".to_owned())?, Some("user".to_owned()));
+        assert_eq!(store.get_cf("logs", "key".to_owned())?, Some("x".repeat(1000)));
+
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(store.get_cf("sessions", "key".to_owned())?, None);
+        assert!(store.remove_cf("sessions", "key".to_owned()).is_err());
+
+        store.drop_family("users")?;
+        assert!(matches!(
+            store.get_cf("users", "batch".to_owned()),
+            Err(KvErr::UnknownFamily(_))
+        ));
+        store.create_family("users", FamilyOptions::default())?;
+        assert_eq!(store.get_cf("users", "batch".to_owned())?, None);
+        drop(store);
+
+        let mut store = KvStore::open_with(dir, options.clone())?;
+        assert_eq!(store.get_cf("users", "batch".to_owned())?, None);
+        store.compact_now()?;
+        assert_eq!(store.stats().live_keys, 2);
+        assert_eq!(store.get("batch".to_owned())?, Some("default".to_owned()));
+        assert_eq!(store.get_cf("logs", "key".to_owned())?, Some("x".repeat(1000)));
+        drop(store);
+
+        // a batch cut short by a crash is dropped as a whole
+        let mut store = KvStore::open_with(dir, options.clone())?;
+        let mut batch = WriteBatch::new();
+        batch
+            .set("torn1".to_owned(), "value".to_owned())
+            .set_cf("logs", "torn2".to_owned(), "value".to_owned());
+        store.write_batch(batch)?;
+        drop(store);
+        let active = dir.join(format!(
+            "store_file_{}.txt",
+            manifest_files(dir).last().unwrap()
+        ));
+        let len = fs::metadata(&active)?.len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&active)?
+            .set_len(len - 5)?;
+        let mut store = KvStore::open_with(dir, options)?;
+        assert_eq!(store.get("torn1".to_owned())?, None);
+        assert_eq!(store.get_cf("logs", "torn2".to_owned())?, None);
+        assert_eq!(store.get("batch".to_owned())?, Some("default".to_owned()));
+        store.set("after".to_owned(), "crash".to_owned())?;
+        assert_eq!(store.get("after".to_owned())?, Some("crash".to_owned()));
+    }
+    Ok(())
+}
+
+// Watchers receive the writes of their prefix in order and can resume from a sequence number.
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:0".to_owned(), "before".to_owned())?;
+    let watcher = store.watch("user:");
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("post:1".to_owned(), "hello".to_owned())?;
+    store.set_many(vec![("user:2".to_owned(), "bob".to_owned())])?;
+    store.remove("user:1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("user:3".to_owned(), "carol".to_owned())
+        .remove("post:1".to_owned());
+    store.write_batch(batch)?;
+
+    let events: Vec<WatchEvent> = watcher.try_iter().collect();
+    let keys: Vec<&str> = events.iter().map(WatchEvent::key).collect();
+    assert_eq!(keys, ["user:1", "user:2", "user:1", "user:3"]);
+    assert!(events.windows(2).all(|pair| pair[0].seq() < pair[1].seq()));
+    assert_eq!(
+        events[2],
+        WatchEvent::Rm {
+            seq: events[2].seq(),
+            key: "user:1".to_owned()
+        }
+    );
+
+    // resume after the first event from the data files
+    let resume_seq = events[0].seq();
+    drop(watcher);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch_from("user:", resume_seq)?;
+    store.set("user:4".to_owned(), "dave".to_owned())?;
+    let resumed: Vec<WatchEvent> = watcher.try_iter().collect();
+    assert_eq!(&resumed[..3], &events[1..]);
+    assert_eq!(
+        resumed[3],
+        WatchEvent::Set {
+            seq: events[3].seq() + 2,
+            key: "user:4".to_owned(),
+            value: "dave".to_owned()
+        }
+    );
+
+    // values written by set_reader are sent without their bytes, live and on resume
+    let seq = store.last_sequence();
+    store.set_reader("user:5".to_owned(), &[0xff, 0xfe][..], 2)?;
+    let event = WatchEvent::SetStream {
+        seq: seq + 1,
+        key: "user:5".to_owned(),
+    };
+    let live: Vec<WatchEvent> = watcher.try_iter().collect();
+    let resumed: Vec<WatchEvent> = store.watch_from("user:", seq)?.try_iter().collect();
+    assert_eq!(live, [event]);
+    assert_eq!(resumed, live);
+    Ok(())
+}
+
+// Watchers of a read-only handle receive the writer's writes picked up by `refresh`.
+#[test]
+fn watch_read_only() -> Result<()> {
+    let index_modes = [IndexMode::Memory, IndexMode::Disk { cache_pages: 4 }];
+    for index_mode in index_modes {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dir = temp_dir.path();
+        let options = Options {
+            index_mode,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with(dir, options.clone())?;
+        store.set("user:0".to_owned(), "before".to_owned())?;
+        let mut reader = KvStore::open_with(
+            dir,
+            Options {
+                read_only: true,
+                ..options
+            },
+        )?;
+        let watcher = reader.watch("user:");
+        let set = store.set("user:1".to_owned(), "alice".to_owned())?;
+        store.set("post:1".to_owned(), "hello".to_owned())?;
+        let removed = store.remove("user:1".to_owned())?;
+        reader.refresh()?;
+        let events: Vec<WatchEvent> = watcher.try_iter().collect();
+        assert_eq!(
+            events,
+            [
+                WatchEvent::Set {
+                    seq: set,
+                    key: "user:1".to_owned(),
+                    value: "alice".to_owned()
+                },
+                WatchEvent::Rm {
+                    seq: removed,
+                    key: "user:1".to_owned()
+                }
+            ]
+        );
+        reader.refresh()?;
+        assert_eq!(watcher.try_iter().count(), 0);
+
+        // writes covered by a new checkpoint of the disk index or moved by compaction
+        // are sent once
+        for key_id in 0..5000 {
+            store.set(format!("user:{}", key_id), "value".to_owned())?;
+        }
+        store.compact_now()?;
+        let last = store.set("user:last".to_owned(), "value".to_owned())?;
+        reader.refresh()?;
+        let events: Vec<WatchEvent> = watcher.try_iter().collect();
+        assert_eq!(events.len(), 5001);
+        assert!(events.windows(2).all(|pair| pair[0].seq() < pair[1].seq()));
+        assert_eq!(events[5000].seq(), last);
+    }
+    Ok(())
+}
+
+// Sequence numbers increase with every write and survive reopening and compaction.
+#[test]
+fn sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), 0);
+    let first = store.set("key1".to_owned(), "value1".to_owned())?;
+    let second = store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(first < second);
+    let removed = store.remove("key1".to_owned())?;
+    assert!(second < removed);
+    assert_eq!(store.last_sequence(), removed);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), removed);
+    for iter in 0..100 {
+        store.set("key2".to_owned(), format!("{}", iter))?;
+    }
+    let last = store.last_sequence();
+    store.compact_now()?;
+    assert_eq!(store.last_sequence(), last);
+    // the tombstone of key1 is gone, watchers can not resume before it
+    assert!(matches!(
+        store.watch_from("key", removed - 1),
+        Err(KvErr::Compacted(seq)) if seq == removed
+    ));
+    // the compacted record of key2 keeps its sequence number
+    let events: Vec<WatchEvent> = store.watch_from("key", removed)?.try_iter().collect();
+    assert_eq!(
+        events,
+        [WatchEvent::Set {
+            seq: last,
+            key: "key2".to_owned(),
+            value: "99".to_owned()
+        }]
+    );
+    drop(store);
+
+    // the sequence number of a tombstone dropped by compaction is not reused
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_sequence(), last);
+    assert_eq!(store.set("key3".to_owned(), "value3".to_owned())?, last + 1);
+
+    // every kind of write returns the sequence number it was given
+    let seqs = store.set_many(vec![
+        ("key4".to_owned(), "value4".to_owned()),
+        ("key5".to_owned(), "value5".to_owned()),
+    ])?;
+    assert_eq!(seqs, [last + 2, last + 3]);
+    let value = b"streamed";
+    let streamed = store.set_reader("key6".to_owned(), &value[..], value.len() as u64)?;
+    assert_eq!(streamed, last + 4);
+    store.create_family("users", FamilyOptions::default())?;
+    assert_eq!(
+        store.set_cf("users", "key".to_owned(), "v".to_owned())?,
+        last + 5
+    );
+    assert_eq!(store.remove_cf("users", "key".to_owned())?, last + 6);
+    let mut batch = WriteBatch::new();
+    batch.set("key7".to_owned(), "value7".to_owned());
+    batch.remove("key4".to_owned());
+    assert_eq!(store.write_batch(batch)?, last + 8);
+    // a batch that writes nothing returns the last sequence number
+    let mut batch = WriteBatch::new();
+    batch.remove("missing".to_owned());
+    assert_eq!(store.write_batch(batch)?, last + 8);
+    assert_eq!(store.last_sequence(), last + 8);
+    Ok(())
+}
+
+// Counters are parsed, updated and written back in one call.
+#[test]
+fn incr_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("counter".to_owned(), 2)?, 7);
+    assert_eq!(store.decr("counter".to_owned(), 10)?, -3);
+    assert_eq!(store.get("counter".to_owned())?, Some("-3".to_owned()));
+
+    store.set("name".to_owned(), "alice".to_owned())?;
+    assert!(matches!(
+        store.incr("name".to_owned(), 1),
+        Err(KvErr::NotAnInteger(_))
+    ));
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.incr("max".to_owned(), 1),
+        Err(KvErr::IntegerOverflow(_))
+    ));
+    assert!(matches!(
+        store.decr("zero".to_owned(), i64::MIN),
+        Err(KvErr::IntegerOverflow(_))
+    ));
+    assert_eq!(store.decr("zero".to_owned(), i64::MIN + 1)?, i64::MAX);
+    store.set("minus_one".to_owned(), "-1".to_owned())?;
+    assert_eq!(store.decr("minus_one".to_owned(), i64::MIN)?, i64::MAX);
+    assert_eq!(store.get("max".to_owned())?, Some(i64::MAX.to_string()));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "counter"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("-2").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "counter", "-8"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("-10").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["incr", "name", "1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("not an integer"));
+    Ok(())
+}
+
+// Appends are joined on read, survive reopening and are merged by compaction.
+#[test]
+fn append_values() -> Result<()> {
+    let index_modes = [IndexMode::Memory, IndexMode::Disk { cache_pages: 4 }];
+    for index_mode in index_modes {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options {
+            index_mode,
+            ..Options::default()
+        };
+        let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        let watcher = store.watch("log");
+        store.append("log".to_owned(), "a".to_owned())?;
+        store.append("log".to_owned(), "b".to_owned())?;
+        store.set("base".to_owned(), "x".to_owned())?;
+        for _ in 0..40 {
+            store.append("base".to_owned(), "y".to_owned())?;
+        }
+        store.append("log".to_owned(), "c".to_owned())?;
+        let events: Vec<WatchEvent> = watcher.try_iter().collect();
+        assert_eq!(
+            events[1],
+            WatchEvent::Append {
+                seq: events[1].seq(),
+                key: "log".to_owned(),
+                suffix: "b".to_owned()
+            }
+        );
+
+        let expected = format!("x{}", "y".repeat(40));
+        assert_eq!(store.get("log".to_owned())?, Some("abc".to_owned()));
+        assert_eq!(store.get("base".to_owned())?, Some(expected.clone()));
+        assert_eq!(
+            store.get_many(&["base".to_owned(), "log".to_owned()])?,
+            vec![Some(expected.clone()), Some("abc".to_owned())]
+        );
+        assert_eq!(
+            store.get_range("log".to_owned(), 1..2)?,
+            Some(b"b".to_vec())
+        );
+        assert_eq!(
+            store.get_range("log".to_owned(), 2..10)?,
+            Some(b"c".to_vec())
+        );
+        assert_eq!(store.get_range("missing".to_owned(), 0..1)?, None);
+        drop(store);
+
+        let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        assert_eq!(store.get("log".to_owned())?, Some("abc".to_owned()));
+        assert_eq!(store.get("base".to_owned())?, Some(expected.clone()));
+        store.remove("log".to_owned())?;
+        store.append("log".to_owned(), "d".to_owned())?;
+        assert_eq!(store.get("log".to_owned())?, Some("d".to_owned()));
+        store.compact_now()?;
+        assert_eq!(store.get("log".to_owned())?, Some("d".to_owned()));
+        assert_eq!(store.get("base".to_owned())?, Some(expected.clone()));
+        store.append("log".to_owned(), "e".to_owned())?;
+        drop(store);
+
+        let mut store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("log".to_owned())?, Some("de".to_owned()));
+        assert_eq!(store.get("base".to_owned())?, Some(expected));
+        assert_eq!(store.stats().live_keys, 2);
+    }
+    Ok(())
+}
+
+struct SumOperator;
+
+impl MergeOperator for SumOperator {
+    fn name(&self) -> &str {
+        "sum"
+    }
+
+    fn merge(&self, _key: &str, existing: Option<&str>, operand: &str) -> String {
+        let existing: i64 = existing.and_then(|value| value.parse().ok()).unwrap_or(0);
+        let operand: i64 = operand.parse().unwrap_or(0);
+        (existing + operand).to_string()
+    }
+}
+
+// Merge operands are folded by the registered operator on read and by compaction.
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.merge("sum".to_owned(), "1".to_owned()),
+        Err(KvErr::NoMergeOperator)
+    ));
+    drop(store);
+
+    let options = Options {
+        merge_operator: Some(Arc::new(SumOperator)),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.merge("sum".to_owned(), "1".to_owned())?;
+    store.merge("sum".to_owned(), "2".to_owned())?;
+    store.set("base".to_owned(), "10".to_owned())?;
+    store.merge("base".to_owned(), "5".to_owned())?;
+    store.append("base".to_owned(), "0".to_owned())?;
+    assert_eq!(store.get("sum".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("base".to_owned())?, Some("150".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    assert_eq!(store.get("sum".to_owned())?, Some("3".to_owned()));
+    store.merge("sum".to_owned(), "-4".to_owned())?;
+    assert_eq!(store.get("sum".to_owned())?, Some("-1".to_owned()));
+    drop(store);
+
+    // the operands can not be read without the operator, or with another one
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(
+        store.get("sum".to_owned()),
+        Err(KvErr::NoMergeOperator)
+    ));
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvErr::InvalidOptions(_))
+    ));
+    struct OtherOperator;
+    impl MergeOperator for OtherOperator {
+        fn name(&self) -> &str {
+            "other"
+        }
+
+        fn merge(&self, _key: &str, _existing: Option<&str>, operand: &str) -> String {
+            operand.to_owned()
+        }
+    }
+    let other = Options {
+        merge_operator: Some(Arc::new(OtherOperator)),
+        ..Options::default()
+    };
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), other.clone()),
+        Err(KvErr::InvalidOptions(_))
+    ));
+    let other_read_only = Options {
+        read_only: true,
+        ..other
+    };
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), other_read_only.clone()),
+        Err(KvErr::InvalidOptions(_))
+    ));
+    // a reader opened before the first operand finds the operator on refresh
+    let fresh_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut writer = KvStore::open_with(fresh_dir.path(), options.clone())?;
+    let mut reader = KvStore::open_with(fresh_dir.path(), other_read_only)?;
+    writer.merge("sum".to_owned(), "1".to_owned())?;
+    assert!(matches!(reader.refresh(), Err(KvErr::InvalidOptions(_))));
+    drop(writer);
+
+    // compaction writes the merged values back
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    store.compact_now()?;
+    drop(store);
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("sum".to_owned())?, Some("-1".to_owned()));
+    assert_eq!(store.get("base".to_owned())?, Some("150".to_owned()));
+    Ok(())
+}